@@ -1,12 +1,19 @@
 pub mod alu;
+pub mod boot;
+pub mod bus;
 pub mod cartridge;
 mod cb;
+#[cfg(test)]
+mod json_tests;
 pub mod ram;
 pub mod registers;
 
+pub use self::bus::Bus;
+
+use std::fmt;
+
 use self::alu::*;
 use self::cb::*;
-use self::ram::*;
 use self::registers::*;
 
 pub fn u8tou16(l: u8, h: u8) -> u16 {
@@ -26,19 +33,99 @@ pub enum CpuState {
     Wait(u8),
     Halt,
     Stop,
+    // LD B,B used as a software breakpoint by test roms
+    Breakpoint,
+}
+
+// everything the processor holds, saved as is by save states
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Cpu {
+    pub reg: Registers,
+    pub halted: bool,
+    // machine cycles left before the next instruction
+    pub wait: u8,
+    // machine cycles since power on
+    pub cycles: u64,
+}
+
+impl fmt::Display for Cpu {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "AF:{:04x} BC:{:04x} DE:{:04x} HL:{:04x} SP:{:04x} PC:{:04x} {} {}{}",
+            self.reg.af(),
+            self.reg.bc(),
+            self.reg.de(),
+            self.reg.hl(),
+            self.reg.sp,
+            self.reg.pc,
+            self.reg.flags,
+            if self.reg.ime { "IME" } else { "---" },
+            if self.halted { " HALT" } else { "" }
+        )
+    }
+}
+
+impl Cpu {
+    pub fn origin() -> Cpu {
+        Cpu {
+            reg: Registers::origin(),
+            halted: false,
+            wait: 0,
+            cycles: 0,
+        }
+    }
+    // runs one machine cycle, returns what the instruction started on it asked for
+    pub fn step(&mut self, ram: &mut ram::Ram) -> CpuState {
+        self.cycles += 1;
+        if self.halted {
+            return CpuState::None;
+        }
+        if self.wait > 0 {
+            self.wait -= 1;
+            return CpuState::None;
+        }
+        let state = instruct(ram, &mut self.reg);
+        match state {
+            CpuState::Wait(t) => self.wait = t,
+            CpuState::Halt => self.halted = true,
+            _ => {}
+        }
+        ram::io::InterruptManager::try_interrupt(ram, &mut self.reg);
+        state
+    }
+}
+
+// runs one instruction then ticks the bus once for every machine cycle it took
+pub fn instruct<B: Bus>(ram: &mut B, reg: &mut Registers) -> CpuState {
+    let state = execute(ram, reg);
+    let cycles = match state {
+        CpuState::Wait(t) => t + 1,
+        _ => 1,
+    };
+    for _ in 0..cycles {
+        ram.tick();
+    }
+    state
 }
 
-pub fn instruct(ram: &mut Ram, reg: &mut Registers, alu: &mut Alu) -> CpuState {
-    fn read_op(ram: &mut Ram, reg: &mut Registers) -> u8 {
+fn execute<B: Bus>(ram: &mut B, reg: &mut Registers) -> CpuState {
+    fn read_op<B: Bus>(ram: &mut B, reg: &mut Registers) -> u8 {
         let r = ram.read(reg.pc);
             //    print!("{:02x} ",r);
-        reg.pc += 1;
+        reg.pc = reg.pc.wrapping_add(1);
         r
     }
+    if reg.ime_delay {
+        reg.ime_delay = false;
+        reg.ime = true;
+    }
     let i = read_op(ram, reg);
     match i {
-        //NOP LD A,A LD L,L LD H,H LD E,E LD D,D LD C,C LD B,B
-        0x00 | 0x7f | 0x6d | 0x64 | 0x5b | 0x52 | 0x49 | 0x40 => CpuState::None,
+        //NOP LD A,A LD L,L LD H,H LD E,E LD D,D LD C,C
+        0x00 | 0x7f | 0x6d | 0x64 | 0x5b | 0x52 | 0x49 => CpuState::None,
+        //LD B,B
+        0x40 => CpuState::Breakpoint,
         //LD B,C
         0x41 => {
             reg.b = reg.c;
@@ -328,320 +415,356 @@ pub fn instruct(ram: &mut Ram, reg: &mut Registers, alu: &mut Alu) -> CpuState {
         //LD (HL),d8
         0x36 => {
             let d = read_op(ram, reg);
-            ram.write8(reg.l, reg.h, d);
+            ram.write(reg.hl(), d);
             CpuState::Wait(2)
         }
 
         //LD B,(HL)
         0x46 => {
-            reg.b = ram.read8(reg.l, reg.h);
+            reg.b = ram.read(reg.hl());
             CpuState::Wait(1)
         }
         //LD C,(HL)
         0x4e => {
-            reg.c = ram.read8(reg.l, reg.h);
+            reg.c = ram.read(reg.hl());
             CpuState::Wait(1)
         }
         //LD D,(HL)
         0x56 => {
-            reg.d = ram.read8(reg.l, reg.h);
+            reg.d = ram.read(reg.hl());
             CpuState::Wait(1)
         }
         //LD E,(HL)
         0x5e => {
-            reg.e = ram.read8(reg.l, reg.h);
+            reg.e = ram.read(reg.hl());
             CpuState::Wait(1)
         }
         //LD H,(HL)
         0x66 => {
-            reg.h = ram.read8(reg.l, reg.h);
+            reg.h = ram.read(reg.hl());
             CpuState::Wait(1)
         }
         //LD L,(HL)
         0x6e => {
-            reg.l = ram.read8(reg.l, reg.h);
+            reg.l = ram.read(reg.hl());
             CpuState::Wait(1)
         }
         //LD A,(HL)
         0x7e => {
-            reg.a = ram.read8(reg.l, reg.h);
+            reg.a = ram.read(reg.hl());
             CpuState::Wait(1)
         }
         //LD A,(HL+)
         0x2a => {
-            reg.a = ram.read8(reg.l, reg.h);
-            alu.inc16(&mut reg.l, &mut reg.h);
+            reg.a = ram.read_idu(reg.hl());
+            reg.set_hl(reg.hl().wrapping_add(1));
             CpuState::Wait(1)
         }
         //LD A,(HL-)
         0x3a => {
-            reg.a = ram.read8(reg.l, reg.h);
-            alu.dec16(&mut reg.l, &mut reg.h);
+            reg.a = ram.read_idu(reg.hl());
+            reg.set_hl(reg.hl().wrapping_sub(1));
             CpuState::Wait(1)
         }
         //LD A,(BC)
         0x0a => {
-            reg.a = ram.read8(reg.c, reg.b);
+            reg.a = ram.read(reg.bc());
             CpuState::Wait(1)
         }
         //LD A,(DE)
         0x1a => {
-            reg.a = ram.read8(reg.e, reg.d);
+            reg.a = ram.read(reg.de());
             CpuState::Wait(1)
         }
 
         //LD (HL),B
         0x70 => {
-            ram.write8(reg.l, reg.h, reg.b);
+            ram.write(reg.hl(), reg.b);
             CpuState::Wait(1)
         }
         //LD (HL),C
         0x71 => {
-            ram.write8(reg.l, reg.h, reg.c);
+            ram.write(reg.hl(), reg.c);
             CpuState::Wait(1)
         }
         //LD (HL),D
         0x72 => {
-            ram.write8(reg.l, reg.h, reg.d);
+            ram.write(reg.hl(), reg.d);
             CpuState::Wait(1)
         }
         //LD (HL),E
         0x73 => {
-            ram.write8(reg.l, reg.h, reg.e);
+            ram.write(reg.hl(), reg.e);
             CpuState::Wait(1)
         }
         //LD (HL),H
         0x74 => {
-            ram.write8(reg.l, reg.h, reg.h);
+            ram.write(reg.hl(), reg.h);
             CpuState::Wait(1)
         }
         //LD (HL),L
         0x75 => {
-            ram.write8(reg.l, reg.h, reg.l);
+            ram.write(reg.hl(), reg.l);
             CpuState::Wait(1)
         }
         //LD (HL),A
         0x77 => {
-            ram.write8(reg.l, reg.h, reg.a);
+            ram.write(reg.hl(), reg.a);
             CpuState::Wait(1)
         }
         //LD (HL+),A
         0x22 => {
-            ram.write8(reg.l, reg.h, reg.a);
-            alu.inc16(&mut reg.l, &mut reg.h);
+            ram.write(reg.hl(), reg.a);
+            reg.set_hl(reg.hl().wrapping_add(1));
             CpuState::Wait(1)
         }
         //LD (HL-),A
         0x32 => {
-            ram.write8(reg.l, reg.h, reg.a);
-            alu.dec16(&mut reg.l, &mut reg.h);
+            ram.write(reg.hl(), reg.a);
+            reg.set_hl(reg.hl().wrapping_sub(1));
             CpuState::Wait(1)
         }
         //LD (BC),A
         0x02 => {
-            ram.write8(reg.c, reg.b, reg.a);
+            ram.write(reg.bc(), reg.a);
             CpuState::Wait(1)
         }
         //LD (DE),A
         0x12 => {
-            ram.write8(reg.e, reg.d, reg.a);
+            ram.write(reg.de(), reg.a);
             CpuState::Wait(1)
         }
 
         //INC A
-        0x3c => alu.inc(&mut reg.a),
+        0x3c => reg.flags.inc(&mut reg.a),
         //INC B
-        0x04 => alu.inc(&mut reg.b),
+        0x04 => reg.flags.inc(&mut reg.b),
         //INC C
-        0x0c => alu.inc(&mut reg.c),
+        0x0c => reg.flags.inc(&mut reg.c),
         //INC D
-        0x14 => alu.inc(&mut reg.d),
+        0x14 => reg.flags.inc(&mut reg.d),
         //INC E
-        0x1c => alu.inc(&mut reg.e),
+        0x1c => reg.flags.inc(&mut reg.e),
         //INC L
-        0x2c => alu.inc(&mut reg.l),
+        0x2c => reg.flags.inc(&mut reg.l),
         //INC H
-        0x24 => alu.inc(&mut reg.h),
+        0x24 => reg.flags.inc(&mut reg.h),
 
         //DEC A
-        0x3d => alu.dec(&mut reg.a),
+        0x3d => reg.flags.dec(&mut reg.a),
         //DEC B
-        0x05 => alu.dec(&mut reg.b),
+        0x05 => reg.flags.dec(&mut reg.b),
         //DEC C
-        0x0d => alu.dec(&mut reg.c),
+        0x0d => reg.flags.dec(&mut reg.c),
         //DEC D
-        0x15 => alu.dec(&mut reg.d),
+        0x15 => reg.flags.dec(&mut reg.d),
         //DEC E
-        0x1d => alu.dec(&mut reg.e),
+        0x1d => reg.flags.dec(&mut reg.e),
         //DEC L
-        0x2d => alu.dec(&mut reg.l),
+        0x2d => reg.flags.dec(&mut reg.l),
         //DEC H
-        0x25 => alu.dec(&mut reg.h),
+        0x25 => reg.flags.dec(&mut reg.h),
 
         //INC BC
-        0x03 => alu.inc16(&mut reg.c, &mut reg.b),
+        0x03 => {
+            ram.idu(reg.bc());
+            reg.set_bc(reg.bc().wrapping_add(1));
+            CpuState::Wait(1)
+        }
         //INC DE
-        0x13 => alu.inc16(&mut reg.e, &mut reg.d),
+        0x13 => {
+            ram.idu(reg.de());
+            reg.set_de(reg.de().wrapping_add(1));
+            CpuState::Wait(1)
+        }
         //INC HL
-        0x23 => alu.inc16(&mut reg.l, &mut reg.h),
+        0x23 => {
+            ram.idu(reg.hl());
+            reg.set_hl(reg.hl().wrapping_add(1));
+            CpuState::Wait(1)
+        }
         //INC SP
         0x33 => {
+            ram.idu(reg.sp);
             reg.sp = reg.sp.wrapping_add(1);
             CpuState::Wait(1)
         }
         //DEC BC
-        0x0b => alu.dec16(&mut reg.c, &mut reg.b),
+        0x0b => {
+            ram.idu(reg.bc());
+            reg.set_bc(reg.bc().wrapping_sub(1));
+            CpuState::Wait(1)
+        }
         //DEC DE
-        0x1b => alu.dec16(&mut reg.e, &mut reg.d),
+        0x1b => {
+            ram.idu(reg.de());
+            reg.set_de(reg.de().wrapping_sub(1));
+            CpuState::Wait(1)
+        }
         //DEC HL
-        0x2b => alu.dec16(&mut reg.l, &mut reg.h),
+        0x2b => {
+            ram.idu(reg.hl());
+            reg.set_hl(reg.hl().wrapping_sub(1));
+            CpuState::Wait(1)
+        }
         //DEC SP
         0x3b => {
+            ram.idu(reg.sp);
             reg.sp = reg.sp.wrapping_sub(1);
             CpuState::Wait(1)
         }
 
         //INC (HL)
         0x34 => {
-            let mut v = ram.read8(reg.l, reg.h);
-            alu.inc(&mut v);
-            ram.write8(reg.l, reg.h, v);
+            let mut v = ram.read(reg.hl());
+            reg.flags.inc(&mut v);
+            ram.write(reg.hl(), v);
             CpuState::Wait(2)
         }
         //DEC (HL)
         0x35 => {
-            let mut v = ram.read8(reg.l, reg.h);
-            alu.dec(&mut v);
-            ram.write8(reg.l, reg.h, v);
+            let mut v = ram.read(reg.hl());
+            reg.flags.dec(&mut v);
+            ram.write(reg.hl(), v);
             CpuState::Wait(2)
         }
 
         //ADD A,B
-        0x80 => alu.add(&mut reg.a, reg.b),
+        0x80 => reg.flags.add(&mut reg.a, reg.b),
         //ADD A,C
-        0x81 => alu.add(&mut reg.a, reg.c),
+        0x81 => reg.flags.add(&mut reg.a, reg.c),
         //ADD A,D
-        0x82 => alu.add(&mut reg.a, reg.d),
+        0x82 => reg.flags.add(&mut reg.a, reg.d),
         //ADD A,E
-        0x83 => alu.add(&mut reg.a, reg.e),
+        0x83 => reg.flags.add(&mut reg.a, reg.e),
         //ADD A,H
-        0x84 => alu.add(&mut reg.a, reg.h),
+        0x84 => reg.flags.add(&mut reg.a, reg.h),
         //ADD A,L
-        0x85 => alu.add(&mut reg.a, reg.l),
+        0x85 => reg.flags.add(&mut reg.a, reg.l),
         //ADD A,(HL)
         0x86 => {
-            alu.add(&mut reg.a, ram.read8(reg.l, reg.h));
+            let v = ram.read(reg.hl());
+            reg.flags.add(&mut reg.a, v);
             CpuState::Wait(1)
         }
         //ADD A,A
         0x87 => {
             let a = reg.a;
-            alu.add(&mut reg.a, a)
+            reg.flags.add(&mut reg.a, a)
         }
 
         //ADD HL,BC
-        0x09 => alu.add16(&mut reg.l, &mut reg.h, u8tou16(reg.c, reg.b)),
+        0x09 => {
+            let bc = reg.bc();
+            reg.flags.add16(&mut reg.l, &mut reg.h, bc)
+        },
         //ADD HL,DE
-        0x19 => alu.add16(&mut reg.l, &mut reg.h, u8tou16(reg.e, reg.d)),
+        0x19 => {
+            let de = reg.de();
+            reg.flags.add16(&mut reg.l, &mut reg.h, de)
+        },
         //ADD HL,HL
         0x29 => {
-            let hl = u8tou16(reg.l, reg.h);
-            alu.add16(&mut reg.l, &mut reg.h, hl)
+            let hl = reg.hl();
+            reg.flags.add16(&mut reg.l, &mut reg.h, hl)
         }
         //ADD HL,SP
-        0x39 => alu.add16(&mut reg.l, &mut reg.h, reg.sp),
+        0x39 => reg.flags.add16(&mut reg.l, &mut reg.h, reg.sp),
 
         //ADC A,B
-        0x88 => alu.adc(&mut reg.a, reg.b),
+        0x88 => reg.flags.adc(&mut reg.a, reg.b),
         //ADC A,C
-        0x89 => alu.adc(&mut reg.a, reg.c),
+        0x89 => reg.flags.adc(&mut reg.a, reg.c),
         //ADC A,D
-        0x8a => alu.adc(&mut reg.a, reg.d),
+        0x8a => reg.flags.adc(&mut reg.a, reg.d),
         //ADC A,E
-        0x8b => alu.adc(&mut reg.a, reg.e),
+        0x8b => reg.flags.adc(&mut reg.a, reg.e),
         //ADC A,H
-        0x8c => alu.adc(&mut reg.a, reg.h),
+        0x8c => reg.flags.adc(&mut reg.a, reg.h),
         //ADC A,L
-        0x8d => alu.adc(&mut reg.a, reg.l),
+        0x8d => reg.flags.adc(&mut reg.a, reg.l),
         //ADC A,(HL)
         0x8e => {
-            alu.adc(&mut reg.a, ram.read8(reg.l, reg.h));
+            let v = ram.read(reg.hl());
+            reg.flags.adc(&mut reg.a, v);
             CpuState::Wait(1)
         }
         //ADC A,A
         0x8f => {
             let a = reg.a;
-            alu.adc(&mut reg.a, a)
+            reg.flags.adc(&mut reg.a, a)
         }
 
         //SUB B
-        0x90 => alu.sub(&mut reg.a, reg.b),
+        0x90 => reg.flags.sub(&mut reg.a, reg.b),
         //SUB C
-        0x91 => alu.sub(&mut reg.a, reg.c),
+        0x91 => reg.flags.sub(&mut reg.a, reg.c),
         //SUB D
-        0x92 => alu.sub(&mut reg.a, reg.d),
+        0x92 => reg.flags.sub(&mut reg.a, reg.d),
         //SUB E
-        0x93 => alu.sub(&mut reg.a, reg.e),
+        0x93 => reg.flags.sub(&mut reg.a, reg.e),
         //SUB H
-        0x94 => alu.sub(&mut reg.a, reg.h),
+        0x94 => reg.flags.sub(&mut reg.a, reg.h),
         //SUB L
-        0x95 => alu.sub(&mut reg.a, reg.l),
+        0x95 => reg.flags.sub(&mut reg.a, reg.l),
         //SUB (HL)
         0x96 => {
-            alu.sub(&mut reg.a, ram.read8(reg.l, reg.h));
+            let v = ram.read(reg.hl());
+            reg.flags.sub(&mut reg.a, v);
             CpuState::Wait(1)
         }
         //SUB A
         0x97 => {
             let a = reg.a;
-            alu.sub(&mut reg.a, a)
+            reg.flags.sub(&mut reg.a, a)
         }
         //SUB d8
         0xd6 => {
             let arg1 = read_op(ram, reg);
-            alu.sub(&mut reg.a, arg1);
+            reg.flags.sub(&mut reg.a, arg1);
             CpuState::Wait(1)
         }
 
         //SBC A,B
-        0x98 => alu.sbc(&mut reg.a, reg.b),
+        0x98 => reg.flags.sbc(&mut reg.a, reg.b),
         //SBC A,C
-        0x99 => alu.sbc(&mut reg.a, reg.c),
+        0x99 => reg.flags.sbc(&mut reg.a, reg.c),
         //SBC A,D
-        0x9a => alu.sbc(&mut reg.a, reg.d),
+        0x9a => reg.flags.sbc(&mut reg.a, reg.d),
         //SBC A,E
-        0x9b => alu.sbc(&mut reg.a, reg.e),
+        0x9b => reg.flags.sbc(&mut reg.a, reg.e),
         //SBC A,H
-        0x9c => alu.sbc(&mut reg.a, reg.h),
+        0x9c => reg.flags.sbc(&mut reg.a, reg.h),
         //SBC A,L
-        0x9d => alu.sbc(&mut reg.a, reg.l),
+        0x9d => reg.flags.sbc(&mut reg.a, reg.l),
         //SBC A,(HL)
         0x9e => {
-            alu.sbc(&mut reg.a, ram.read8(reg.l, reg.h));
+            let v = ram.read(reg.hl());
+            reg.flags.sbc(&mut reg.a, v);
             CpuState::Wait(1)
         }
         //SBC A,A
         0x9f => {
             let a = reg.a;
-            alu.sbc(&mut reg.a, a)
+            reg.flags.sbc(&mut reg.a, a)
         }
         //SBC A,d8
         0xde => {
             let arg1 = read_op(ram, reg);
-            alu.sbc(&mut reg.a, arg1);
+            reg.flags.sbc(&mut reg.a, arg1);
             CpuState::Wait(1)
         }
 
         //ADD A,d8
         0xc6 => {
             let arg1 = read_op(ram, reg);
-            alu.add(&mut reg.a, arg1);
+            reg.flags.add(&mut reg.a, arg1);
             CpuState::Wait(1)
         }
         //ADC A,d8
         0xce => {
             let arg1 = read_op(ram, reg);
-            alu.adc(&mut reg.a, arg1);
+            reg.flags.adc(&mut reg.a, arg1);
             CpuState::Wait(1)
         }
 
@@ -649,11 +772,11 @@ pub fn instruct(ram: &mut Ram, reg: &mut Registers, alu: &mut Alu) -> CpuState {
         0xe8 => {
             let b = read_op(ram, reg);
             let bb = u8toi16(b);
-            alu.flag_halfcarry = ((reg.sp & 0xf) + (bb & 0xf)) > 0xf;
-            alu.flag_carry = ((reg.sp & 0xff) + (bb & 0xff)) > 0xff;
-            alu.flag_substract = false;
+            reg.flags.flag_halfcarry = ((reg.sp & 0xf) + (bb & 0xf)) > 0xf;
+            reg.flags.flag_carry = ((reg.sp & 0xff) + (bb & 0xff)) > 0xff;
+            reg.flags.flag_substract = false;
             reg.sp = reg.sp.wrapping_add(bb);
-            alu.flag_zero = false;
+            reg.flags.flag_zero = false;
             CpuState::Wait(3)
         }
 
@@ -661,200 +784,204 @@ pub fn instruct(ram: &mut Ram, reg: &mut Registers, alu: &mut Alu) -> CpuState {
         0x07 => {
             let c = (reg.a & 0x80) != 0;
             reg.a = (reg.a << 1) + c as u8;
-            alu.flag_carry = c;
-            //           alu.Fzero = reg.A == 0;
-            alu.flag_zero = false;
-            alu.flag_substract = false;
-            alu.flag_halfcarry = false;
+            reg.flags.flag_carry = c;
+            //           reg.flags.Fzero = reg.A == 0;
+            reg.flags.flag_zero = false;
+            reg.flags.flag_substract = false;
+            reg.flags.flag_halfcarry = false;
             CpuState::None
         }
         //RRCA
         0x0f => {
             let c = (reg.a & 1) != 0;
             reg.a = (reg.a >> 1) + if c { 0x80 } else { 0 };
-            alu.flag_carry = c;
-            //            alu.Fzero = reg.A == 0;
-            alu.flag_zero = false;
-            alu.flag_substract = false;
-            alu.flag_halfcarry = false;
+            reg.flags.flag_carry = c;
+            //            reg.flags.Fzero = reg.A == 0;
+            reg.flags.flag_zero = false;
+            reg.flags.flag_substract = false;
+            reg.flags.flag_halfcarry = false;
             CpuState::None
         }
         //RLA
         0x17 => {
             let c = (reg.a & 0x80) != 0;
-            reg.a = (reg.a << 1) + alu.flag_carry as u8;
-            alu.flag_carry = c;
-            //            alu.Fzero =reg.A == 0;
-            alu.flag_zero = false;
-            alu.flag_substract = false;
-            alu.flag_halfcarry = false;
+            reg.a = (reg.a << 1) + reg.flags.flag_carry as u8;
+            reg.flags.flag_carry = c;
+            //            reg.flags.Fzero =reg.A == 0;
+            reg.flags.flag_zero = false;
+            reg.flags.flag_substract = false;
+            reg.flags.flag_halfcarry = false;
             CpuState::None
         }
         //RRA
         0x1f => {
             let c = (reg.a & 1) != 0;
-            reg.a = (reg.a >> 1) + if alu.flag_carry { 0x80 } else { 0 };
-            alu.flag_carry = c;
-            //alu.Fzero = reg.A == 0;
-            alu.flag_zero = false;
-            alu.flag_substract = false;
-            alu.flag_halfcarry = false;
+            reg.a = (reg.a >> 1) + if reg.flags.flag_carry { 0x80 } else { 0 };
+            reg.flags.flag_carry = c;
+            //reg.flags.Fzero = reg.A == 0;
+            reg.flags.flag_zero = false;
+            reg.flags.flag_substract = false;
+            reg.flags.flag_halfcarry = false;
             CpuState::None
         }
         //DAA
         0x27 => {
-            /*if alu.Fhalf || (reg.A & 0x0f) > 9{
+            /*if reg.flags.Fhalf || (reg.A & 0x0f) > 9{
                 reg.A = reg.A.wrapping_add(6);
             }
-            if alu.Fcarry || (reg.A >> 4) >9{
+            if reg.flags.Fcarry || (reg.A >> 4) >9{
                 reg.A = reg.A.wrapping_add(0x60);
-                alu.Fcarry = true;
+                reg.flags.Fcarry = true;
             }*/
             /*
-                        if alu.Fsub {
-                            if alu.Fhalf || (reg.A & 0xf) > 0x9 {
+                        if reg.flags.Fsub {
+                            if reg.flags.Fhalf || (reg.A & 0xf) > 0x9 {
                                 reg.A = reg.A.wrapping_sub(0x6);
                             }
-                            if alu.Fcarry || (reg.A >> 4) > 0x9 {
+                            if reg.flags.Fcarry || (reg.A >> 4) > 0x9 {
                                 reg.A = reg.A.wrapping_sub(0x60);
                             }
                         }else{
 
-                            if alu.Fhalf || (reg.A & 0xf) > 0x9 {
+                            if reg.flags.Fhalf || (reg.A & 0xf) > 0x9 {
                                 reg.A = reg.A.wrapping_add(0x6);
                             }
-                            if alu.Fcarry || reg.A > 0x9f {
+                            if reg.flags.Fcarry || reg.A > 0x9f {
                                 reg.A = reg.A.wrapping_add(0x60);
-                                alu.Fcarry = true;
+                                reg.flags.Fcarry = true;
                             }
 
                         }
             */
-            if !alu.flag_substract {
-                if alu.flag_carry || reg.a > 0x99 {
+            if !reg.flags.flag_substract {
+                if reg.flags.flag_carry || reg.a > 0x99 {
                     reg.a = reg.a.wrapping_add(0x60);
-                    alu.flag_carry = true;
+                    reg.flags.flag_carry = true;
                 }
-                if alu.flag_halfcarry || (reg.a & 0xF) > 0x9 {
+                if reg.flags.flag_halfcarry || (reg.a & 0xF) > 0x9 {
                     reg.a = reg.a.wrapping_add(0x06);
                 }
-            } else if alu.flag_carry && alu.flag_halfcarry {
+            } else if reg.flags.flag_carry && reg.flags.flag_halfcarry {
                 reg.a = reg.a.wrapping_add(0x9A);
-            } else if alu.flag_carry {
+            } else if reg.flags.flag_carry {
                 reg.a = reg.a.wrapping_add(0xA0);
-            } else if alu.flag_halfcarry {
+            } else if reg.flags.flag_halfcarry {
                 reg.a = reg.a.wrapping_add(0xFA);
             }
-            alu.flag_zero = reg.a == 0;
-            alu.flag_halfcarry = false;
+            reg.flags.flag_zero = reg.a == 0;
+            reg.flags.flag_halfcarry = false;
             CpuState::None
         }
         //CPL
         0x2f => {
             reg.a = !reg.a;
-            alu.flag_substract = true;
-            alu.flag_halfcarry = true;
+            reg.flags.flag_substract = true;
+            reg.flags.flag_halfcarry = true;
             CpuState::None
         }
 
         //SCF set carry flag
         0x37 => {
-            alu.flag_carry = true;
-            alu.flag_substract = false;
-            alu.flag_halfcarry = false;
+            reg.flags.flag_carry = true;
+            reg.flags.flag_substract = false;
+            reg.flags.flag_halfcarry = false;
             CpuState::None
         }
         //CCF complement not clear carry flag
         0x3f => {
-            alu.flag_carry = !alu.flag_carry;
-            alu.flag_substract = false;
-            alu.flag_halfcarry = false;
+            reg.flags.flag_carry = !reg.flags.flag_carry;
+            reg.flags.flag_substract = false;
+            reg.flags.flag_halfcarry = false;
             CpuState::None
         }
 
         //AND
-        0xa0 => alu.and(&mut reg.a, reg.b),
-        0xa1 => alu.and(&mut reg.a, reg.c),
-        0xa2 => alu.and(&mut reg.a, reg.d),
-        0xa3 => alu.and(&mut reg.a, reg.e),
-        0xa4 => alu.and(&mut reg.a, reg.h),
-        0xa5 => alu.and(&mut reg.a, reg.l),
+        0xa0 => reg.flags.and(&mut reg.a, reg.b),
+        0xa1 => reg.flags.and(&mut reg.a, reg.c),
+        0xa2 => reg.flags.and(&mut reg.a, reg.d),
+        0xa3 => reg.flags.and(&mut reg.a, reg.e),
+        0xa4 => reg.flags.and(&mut reg.a, reg.h),
+        0xa5 => reg.flags.and(&mut reg.a, reg.l),
         0xa6 => {
-            alu.and(&mut reg.a, ram.read8(reg.l, reg.h));
+            let v = ram.read(reg.hl());
+            reg.flags.and(&mut reg.a, v);
             CpuState::Wait(1)
         }
         0xa7 => {
             let a = reg.a;
-            alu.and(&mut reg.a, a)
+            reg.flags.and(&mut reg.a, a)
         }
         //AND d8
         0xe6 => {
             let arg1 = read_op(ram, reg);
-            alu.and(&mut reg.a, arg1);
+            reg.flags.and(&mut reg.a, arg1);
             CpuState::Wait(1)
         }
         //XOR
-        0xa8 => alu.xor(&mut reg.a, reg.b),
-        0xa9 => alu.xor(&mut reg.a, reg.c),
-        0xaa => alu.xor(&mut reg.a, reg.d),
-        0xab => alu.xor(&mut reg.a, reg.e),
-        0xac => alu.xor(&mut reg.a, reg.h),
-        0xad => alu.xor(&mut reg.a, reg.l),
+        0xa8 => reg.flags.xor(&mut reg.a, reg.b),
+        0xa9 => reg.flags.xor(&mut reg.a, reg.c),
+        0xaa => reg.flags.xor(&mut reg.a, reg.d),
+        0xab => reg.flags.xor(&mut reg.a, reg.e),
+        0xac => reg.flags.xor(&mut reg.a, reg.h),
+        0xad => reg.flags.xor(&mut reg.a, reg.l),
         0xae => {
-            alu.xor(&mut reg.a, ram.read8(reg.l, reg.h));
+            let v = ram.read(reg.hl());
+            reg.flags.xor(&mut reg.a, v);
             CpuState::Wait(1)
         }
         0xaf => {
             let a = reg.a;
-            alu.xor(&mut reg.a, a)
+            reg.flags.xor(&mut reg.a, a)
         }
         //XOR d8
         0xee => {
             let arg1 = read_op(ram, reg);
-            alu.xor(&mut reg.a, arg1);
+            reg.flags.xor(&mut reg.a, arg1);
             CpuState::Wait(1)
         }
 
         //OR
-        0xb0 => alu.or(&mut reg.a, reg.b),
-        0xb1 => alu.or(&mut reg.a, reg.c),
-        0xb2 => alu.or(&mut reg.a, reg.d),
-        0xb3 => alu.or(&mut reg.a, reg.e),
-        0xb4 => alu.or(&mut reg.a, reg.h),
-        0xb5 => alu.or(&mut reg.a, reg.l),
+        0xb0 => reg.flags.or(&mut reg.a, reg.b),
+        0xb1 => reg.flags.or(&mut reg.a, reg.c),
+        0xb2 => reg.flags.or(&mut reg.a, reg.d),
+        0xb3 => reg.flags.or(&mut reg.a, reg.e),
+        0xb4 => reg.flags.or(&mut reg.a, reg.h),
+        0xb5 => reg.flags.or(&mut reg.a, reg.l),
         0xb6 => {
-            alu.or(&mut reg.a, ram.read8(reg.l, reg.h));
+            let v = ram.read(reg.hl());
+            reg.flags.or(&mut reg.a, v);
             CpuState::Wait(1)
         }
         0xb7 => {
             let a = reg.a;
-            alu.or(&mut reg.a, a)
+            reg.flags.or(&mut reg.a, a)
         }
         //OR d8
         0xf6 => {
             let arg1 = read_op(ram, reg);
-            alu.or(&mut reg.a, arg1);
+            reg.flags.or(&mut reg.a, arg1);
             CpuState::Wait(1)
         }
         //CP
-        0xb8 => alu.cp(reg.a, reg.b),
-        0xb9 => alu.cp(reg.a, reg.c),
-        0xba => alu.cp(reg.a, reg.d),
-        0xbb => alu.cp(reg.a, reg.e),
-        0xbc => alu.cp(reg.a, reg.h),
-        0xbd => alu.cp(reg.a, reg.l),
+        0xb8 => reg.flags.cp(reg.a, reg.b),
+        0xb9 => reg.flags.cp(reg.a, reg.c),
+        0xba => reg.flags.cp(reg.a, reg.d),
+        0xbb => reg.flags.cp(reg.a, reg.e),
+        0xbc => reg.flags.cp(reg.a, reg.h),
+        0xbd => reg.flags.cp(reg.a, reg.l),
         0xbe => {
-            alu.cp(reg.a, ram.read8(reg.l, reg.h));
+            let v = ram.read(reg.hl());
+            reg.flags.cp(reg.a, v);
             CpuState::Wait(1)
         }
         0xbf => {
             let a = reg.a;
-            alu.cp(reg.a, a)
+            reg.flags.cp(reg.a, a)
         }
         //CP d8
         0xfe => {
             let arg1 = read_op(ram, reg);
-            alu.cp(reg.a, arg1);
+            reg.flags.cp(reg.a, arg1);
             CpuState::Wait(1)
         }
 
@@ -894,23 +1021,21 @@ pub fn instruct(ram: &mut Ram, reg: &mut Registers, alu: &mut Alu) -> CpuState {
             let b = read_op(ram, reg);
             let bb = u8toi16(b);
 
-            alu.flag_halfcarry = ((reg.sp & 0xf) + (bb & 0xf)) > 0xf;
-            alu.flag_carry = ((reg.sp & 0xff) + (bb & 0xff)) > 0xff;
-            alu.flag_substract = false;
+            reg.flags.flag_halfcarry = ((reg.sp & 0xf) + (bb & 0xf)) > 0xf;
+            reg.flags.flag_carry = ((reg.sp & 0xff) + (bb & 0xff)) > 0xff;
+            reg.flags.flag_substract = false;
             let r = reg.sp.wrapping_add(bb);
-            alu.flag_zero = false;
+            reg.flags.flag_zero = false;
 
-            let (l, h) = u16tou8(r);
-            reg.l = l;
-            reg.h = h;
-            alu.flag_zero = false;
+            reg.set_hl(r);
+            reg.flags.flag_zero = false;
             //            reg.L = ram.read(r);
             //            reg.H = ram.read(r.wrapping_add(1));
             CpuState::Wait(3)
         }
         //LD SP,HL
         0xf9 => {
-            reg.sp = u8tou16(reg.l, reg.h);
+            reg.sp = reg.hl();
             CpuState::Wait(1)
         }
         //LD A,(a16)
@@ -923,76 +1048,64 @@ pub fn instruct(ram: &mut Ram, reg: &mut Registers, alu: &mut Alu) -> CpuState {
 
         //POP BC
         0xc1 => {
-            reg.c = ram.read(reg.sp);
-            reg.b = ram.read(reg.sp.wrapping_add(1));
-            reg.sp += 2;
+            let v = ram.pop16(&mut reg.sp);
+            reg.set_bc(v);
             CpuState::Wait(3)
         }
         //POP DE
         0xd1 => {
-            reg.e = ram.read(reg.sp);
-            reg.d = ram.read(reg.sp.wrapping_add(1));
-            reg.sp += 2;
+            let v = ram.pop16(&mut reg.sp);
+            reg.set_de(v);
             CpuState::Wait(3)
         }
         //POP HL
         0xe1 => {
-            reg.l = ram.read(reg.sp);
-            reg.h = ram.read(reg.sp.wrapping_add(1));
-            reg.sp += 2;
+            let v = ram.pop16(&mut reg.sp);
+            reg.set_hl(v);
             CpuState::Wait(3)
         }
         //POP AF
         0xf1 => {
-            alu.set_f(ram.read(reg.sp));
-            reg.a = ram.read(reg.sp.wrapping_add(1));
-            reg.sp += 2;
+            let v = ram.pop16(&mut reg.sp);
+            reg.set_af(v);
             CpuState::Wait(3)
         }
 
         //PUSH BC
         0xc5 => {
-            reg.sp -= 2;
-            ram.write(reg.sp, reg.c);
-            ram.write(reg.sp + 1, reg.b);
+            let v = reg.bc();
+            ram.push16(&mut reg.sp, v);
             CpuState::Wait(3)
         }
         //PUSH DE
         0xd5 => {
-            reg.sp -= 2;
-            ram.write(reg.sp, reg.e);
-            ram.write(reg.sp + 1, reg.d);
+            let v = reg.de();
+            ram.push16(&mut reg.sp, v);
             CpuState::Wait(3)
         }
         //PUSH HL
         0xe5 => {
-            reg.sp -= 2;
-            ram.write(reg.sp, reg.l);
-            ram.write(reg.sp + 1, reg.h);
+            let v = reg.hl();
+            ram.push16(&mut reg.sp, v);
             CpuState::Wait(3)
         }
         //PUSH AF
         0xf5 => {
-            reg.sp -= 2;
-            ram.write(reg.sp, alu.get_f());
-            ram.write(reg.sp + 1, reg.a);
+            let v = reg.af();
+            ram.push16(&mut reg.sp, v);
             CpuState::Wait(3)
         }
 
         //JR r8
         0x18 => {
             let arg1 = u8toi16(read_op(ram, reg));
-            if arg1 == 0xfffe {
-                panic!("infinite loop");
-                //TODO should not stay, could still be interrupted
-            }
             reg.pc = reg.pc.wrapping_add(arg1);
             CpuState::Wait(2)
         }
         //JR NZ,r8
         0x20 => {
             let arg1 = u8toi16(read_op(ram, reg));
-            if !alu.flag_zero {
+            if !reg.flags.flag_zero {
                 reg.pc = reg.pc.wrapping_add(arg1);
                 CpuState::Wait(2)
             } else {
@@ -1002,7 +1115,7 @@ pub fn instruct(ram: &mut Ram, reg: &mut Registers, alu: &mut Alu) -> CpuState {
         //JR Z,r8
         0x28 => {
             let arg1 = u8toi16(read_op(ram, reg));
-            if alu.flag_zero {
+            if reg.flags.flag_zero {
                 reg.pc = reg.pc.wrapping_add(arg1);
                 CpuState::Wait(2)
             } else {
@@ -1012,7 +1125,7 @@ pub fn instruct(ram: &mut Ram, reg: &mut Registers, alu: &mut Alu) -> CpuState {
         //JR NC,r8
         0x30 => {
             let arg1 = u8toi16(read_op(ram, reg));
-            if !alu.flag_carry {
+            if !reg.flags.flag_carry {
                 reg.pc = reg.pc.wrapping_add(arg1);
                 CpuState::Wait(2)
             } else {
@@ -1022,7 +1135,7 @@ pub fn instruct(ram: &mut Ram, reg: &mut Registers, alu: &mut Alu) -> CpuState {
         //JR C,r8
         0x38 => {
             let arg1 = u8toi16(read_op(ram, reg));
-            if alu.flag_carry {
+            if reg.flags.flag_carry {
                 reg.pc = reg.pc.wrapping_add(arg1);
                 CpuState::Wait(2)
             } else {
@@ -1034,7 +1147,7 @@ pub fn instruct(ram: &mut Ram, reg: &mut Registers, alu: &mut Alu) -> CpuState {
         0xc2 => {
             let arg1 = read_op(ram, reg);
             let arg2 = read_op(ram, reg);
-            if !alu.flag_zero {
+            if !reg.flags.flag_zero {
                 reg.pc = u8tou16(arg1, arg2);
                 CpuState::Wait(3)
             } else {
@@ -1052,7 +1165,7 @@ pub fn instruct(ram: &mut Ram, reg: &mut Registers, alu: &mut Alu) -> CpuState {
         0xca => {
             let arg1 = read_op(ram, reg);
             let arg2 = read_op(ram, reg);
-            if alu.flag_zero {
+            if reg.flags.flag_zero {
                 reg.pc = u8tou16(arg1, arg2);
                 CpuState::Wait(3)
             } else {
@@ -1063,7 +1176,7 @@ pub fn instruct(ram: &mut Ram, reg: &mut Registers, alu: &mut Alu) -> CpuState {
         0xd2 => {
             let arg1 = read_op(ram, reg);
             let arg2 = read_op(ram, reg);
-            if !alu.flag_carry {
+            if !reg.flags.flag_carry {
                 reg.pc = u8tou16(arg1, arg2);
                 CpuState::Wait(3)
             } else {
@@ -1074,7 +1187,7 @@ pub fn instruct(ram: &mut Ram, reg: &mut Registers, alu: &mut Alu) -> CpuState {
         0xda => {
             let arg1 = read_op(ram, reg);
             let arg2 = read_op(ram, reg);
-            if alu.flag_carry {
+            if reg.flags.flag_carry {
                 reg.pc = u8tou16(arg1, arg2);
                 CpuState::Wait(3)
             } else {
@@ -1083,7 +1196,7 @@ pub fn instruct(ram: &mut Ram, reg: &mut Registers, alu: &mut Alu) -> CpuState {
         }
         //JP (HL)
         0xe9 => {
-            reg.pc = u8tou16(reg.l, reg.h);
+            reg.pc = reg.hl();
             CpuState::Wait(3)
         }
 
@@ -1091,7 +1204,7 @@ pub fn instruct(ram: &mut Ram, reg: &mut Registers, alu: &mut Alu) -> CpuState {
         0xc4 => {
             let arg1 = read_op(ram, reg);
             let arg2 = read_op(ram, reg);
-            if !alu.flag_zero {
+            if !reg.flags.flag_zero {
                 ram.push16(&mut reg.sp, reg.pc);
                 reg.pc = u8tou16(arg1, arg2);
                 CpuState::Wait(7)
@@ -1103,7 +1216,7 @@ pub fn instruct(ram: &mut Ram, reg: &mut Registers, alu: &mut Alu) -> CpuState {
         0xcc => {
             let arg1 = read_op(ram, reg);
             let arg2 = read_op(ram, reg);
-            if alu.flag_zero {
+            if reg.flags.flag_zero {
                 ram.push16(&mut reg.sp, reg.pc);
                 reg.pc = u8tou16(arg1, arg2);
                 CpuState::Wait(7)
@@ -1124,7 +1237,7 @@ pub fn instruct(ram: &mut Ram, reg: &mut Registers, alu: &mut Alu) -> CpuState {
         0xd4 => {
             let arg1 = read_op(ram, reg);
             let arg2 = read_op(ram, reg);
-            if !alu.flag_carry {
+            if !reg.flags.flag_carry {
                 ram.push16(&mut reg.sp, reg.pc);
                 reg.pc = u8tou16(arg1, arg2);
                 CpuState::Wait(7)
@@ -1136,7 +1249,7 @@ pub fn instruct(ram: &mut Ram, reg: &mut Registers, alu: &mut Alu) -> CpuState {
         0xdc => {
             let arg1 = read_op(ram, reg);
             let arg2 = read_op(ram, reg);
-            if alu.flag_carry {
+            if reg.flags.flag_carry {
                 ram.push16(&mut reg.sp, reg.pc);
                 reg.pc = u8tou16(arg1, arg2);
                 CpuState::Wait(7)
@@ -1196,7 +1309,7 @@ pub fn instruct(ram: &mut Ram, reg: &mut Registers, alu: &mut Alu) -> CpuState {
 
         //RET NZ
         0xc0 => {
-            if !alu.flag_zero {
+            if !reg.flags.flag_zero {
                 reg.pc = ram.pop16(&mut reg.sp);
                 CpuState::Wait(4)
             } else {
@@ -1205,7 +1318,7 @@ pub fn instruct(ram: &mut Ram, reg: &mut Registers, alu: &mut Alu) -> CpuState {
         }
         //RET Z
         0xc8 => {
-            if alu.flag_zero {
+            if reg.flags.flag_zero {
                 reg.pc = ram.pop16(&mut reg.sp);
                 CpuState::Wait(4)
             } else {
@@ -1219,7 +1332,7 @@ pub fn instruct(ram: &mut Ram, reg: &mut Registers, alu: &mut Alu) -> CpuState {
         }
         //RET NC
         0xd0 => {
-            if !alu.flag_carry {
+            if !reg.flags.flag_carry {
                 reg.pc = ram.pop16(&mut reg.sp);
                 CpuState::Wait(4)
             } else {
@@ -1228,7 +1341,7 @@ pub fn instruct(ram: &mut Ram, reg: &mut Registers, alu: &mut Alu) -> CpuState {
         }
         //RET C
         0xd8 => {
-            if alu.flag_carry {
+            if reg.flags.flag_carry {
                 reg.pc = ram.pop16(&mut reg.sp);
                 CpuState::Wait(4)
             } else {
@@ -1238,7 +1351,7 @@ pub fn instruct(ram: &mut Ram, reg: &mut Registers, alu: &mut Alu) -> CpuState {
         //RETI
         0xd9 => {
             reg.pc = ram.pop16(&mut reg.sp);
-            ram.interrupt.master_enable = true;
+            reg.ime = true;
             //            println!("RETI PC{:x} SP{:x}",reg.PC,reg.SP);
             //TODO should interrupt be enabled directly or like DI and EI ?
             CpuState::Wait(1)
@@ -1246,13 +1359,14 @@ pub fn instruct(ram: &mut Ram, reg: &mut Registers, alu: &mut Alu) -> CpuState {
 
         //DI
         0xf3 => {
-            ram.interrupt.order_disable = true;
+            reg.ime = false;
+            reg.ime_delay = false;
             //            println!("DI");
             CpuState::None
         }
         //EI
         0xfb => {
-            ram.interrupt.order_enable = true;
+            reg.ime_delay = true;
             //            println!("EI");
             CpuState::None
         }
@@ -1271,7 +1385,7 @@ pub fn instruct(ram: &mut Ram, reg: &mut Registers, alu: &mut Alu) -> CpuState {
         //PREFIX CB
         0xcb => {
             let op = read_op(ram, reg);
-            instr_cb(ram, reg, alu, op)
+            instr_cb(ram, reg, op)
         }
 
         //FIRE
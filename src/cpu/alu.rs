@@ -1,14 +1,15 @@
 use cpu::*;
 use std::fmt;
 
-pub struct Alu {
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Flags {
     pub flag_zero: bool,
     pub flag_substract: bool,
     pub flag_halfcarry: bool,
     pub flag_carry: bool,
 }
 
-impl fmt::Display for Alu {
+impl fmt::Display for Flags {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
@@ -21,16 +22,16 @@ impl fmt::Display for Alu {
     }
 }
 
-impl Alu {
-    pub fn origin() -> Alu {
-        Alu {
+impl Flags {
+    pub fn origin() -> Flags {
+        Flags {
             flag_zero: false,
             flag_substract: false,
             flag_halfcarry: false,
             flag_carry: false,
         }
     }
-    pub fn get_f(&self) -> u8 {
+    pub fn bits(&self) -> u8 {
         let mut r = 0 as u8;
         if self.flag_zero {
             r += 1 << 7
@@ -46,7 +47,7 @@ impl Alu {
         };
         r
     }
-    pub fn set_f(&mut self, f: u8) {
+    pub fn set_bits(&mut self, f: u8) {
         self.flag_zero = (f & 1 << 7) != 0;
         self.flag_substract = (f & 1 << 6) != 0;
         self.flag_halfcarry = (f & 1 << 5) != 0;
@@ -155,20 +156,4 @@ impl Alu {
         self.flag_zero = *a == 0;
         CpuState::None
     }
-    pub fn inc16(&self, l: &mut u8, h: &mut u8) -> CpuState {
-        let mut r = u8tou16(*l, *h);
-        r = r.wrapping_add(1);
-        let (rl, rh) = u16tou8(r);
-        *l = rl;
-        *h = rh;
-        CpuState::Wait(1)
-    }
-    pub fn dec16(&self, l: &mut u8, h: &mut u8) -> CpuState {
-        let mut r = u8tou16(*l, *h);
-        r = r.wrapping_sub(1);
-        let (rl, rh) = u16tou8(r);
-        *l = rl;
-        *h = rh;
-        CpuState::Wait(1)
-    }
 }
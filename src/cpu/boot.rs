@@ -0,0 +1,297 @@
+use cpu::cartridge::Cartridge;
+use cpu::ram::io::video::compat;
+use cpu::ram::io::video::palette::Palette;
+use cpu::ram::Ram;
+use cpu::*;
+
+use std::fs::File;
+use std::io::prelude::*;
+
+// the emulated hardware, their boot roms leave different values behind
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Model {
+    Dmg0,
+    Dmg,
+    Mgb,
+    Sgb,
+    Sgb2,
+    Cgb,
+    Agb,
+}
+
+impl Model {
+    pub fn from_name(name: &str) -> Option<Model> {
+        match name.to_lowercase().as_str() {
+            "dmg0" => Some(Model::Dmg0),
+            "dmg" => Some(Model::Dmg),
+            "mgb" => Some(Model::Mgb),
+            "sgb" => Some(Model::Sgb),
+            "sgb2" => Some(Model::Sgb2),
+            "cgb" => Some(Model::Cgb),
+            "agb" => Some(Model::Agb),
+            _ => None,
+        }
+    }
+
+    // the hardware the cartridge was made for: the CGB for color games, the
+    // SGB for the ones using its functions
+    pub fn from_header(cart: &Cartridge) -> Model {
+        if cart.is_cgb() {
+            Model::Cgb
+        } else if cart.is_sgb() {
+            Model::Sgb
+        } else {
+            Model::Dmg
+        }
+    }
+
+    // the CGB ppu and registers
+    pub fn is_cgb(self) -> bool {
+        self == Model::Cgb || self == Model::Agb
+    }
+
+    pub fn is_sgb(self) -> bool {
+        self == Model::Sgb || self == Model::Sgb2
+    }
+}
+
+// DMG, MGB and SGB boot roms are mapped over 0000-00ff, the CGB one also
+// over 0200-08ff (the cartridge header stays visible in between)
+pub const SHORT_BOOT_ROM: usize = 0x100;
+pub const CGB_BOOT_ROM: usize = 0x900;
+
+pub fn load_boot_rom(path: &str) -> Result<Vec<u8>, String> {
+    let mut f = File::open(path).map_err(|e| format!("can not open boot rom {}: {}", path, e))?;
+    let mut data = Vec::new();
+    f.read_to_end(&mut data)
+        .map_err(|e| format!("can not read boot rom {}: {}", path, e))?;
+    match data.len() {
+        SHORT_BOOT_ROM | CGB_BOOT_ROM => Ok(data),
+        n => Err(format!(
+            "boot rom {} is {} bytes, expected {} (DMG/MGB/SGB) or {} (CGB)",
+            path, n, SHORT_BOOT_ROM, CGB_BOOT_ROM
+        )),
+    }
+}
+
+// the io registers as the boot roms leave them, in the values to write
+// (sound is powered on first, its logo sound is not replayed). The ones
+// differing between models are set in skip_boot, DMA and the CGB registers
+// keep their power on value
+const IO_AFTER_BOOT: [(u16, u8); 34] = [
+    (0xff00, 0x00),
+    (0xff01, 0x00),
+    (0xff05, 0x00),
+    (0xff06, 0x00),
+    (0xff07, 0x00),
+    (0xff26, 0x80),
+    (0xff10, 0x00),
+    (0xff11, 0x80),
+    (0xff12, 0xf3),
+    (0xff13, 0x00),
+    (0xff14, 0x00),
+    (0xff16, 0x00),
+    (0xff17, 0x00),
+    (0xff18, 0x00),
+    (0xff19, 0x00),
+    (0xff1a, 0x00),
+    (0xff1b, 0x00),
+    (0xff1c, 0x00),
+    (0xff1d, 0x00),
+    (0xff1e, 0x00),
+    (0xff20, 0x00),
+    (0xff21, 0x00),
+    (0xff22, 0x00),
+    (0xff23, 0x00),
+    (0xff24, 0x77),
+    (0xff25, 0xf3),
+    (0xff40, 0x91),
+    (0xff42, 0x00),
+    (0xff43, 0x00),
+    (0xff45, 0x00),
+    (0xff47, 0xfc),
+    (0xff4a, 0x00),
+    (0xff4b, 0x00),
+    (0xffff, 0x00),
+];
+
+// the boot rom reads the keys held during the logo before starting the game,
+// a combo picks its palette, else Nintendo titles get their own
+fn compat_palette(ram: &Ram) -> Palette {
+    if let Some(combo) = ram.joypad.combo() {
+        return compat::from_combo(&combo).unwrap();
+    }
+    ram.cart
+        .compat_hash()
+        .and_then(|(hash, letter)| compat::from_title(hash, letter))
+        .unwrap_or_else(compat::default)
+}
+
+// puts the machine in the state the boot rom of `model` leaves it in, at 0x0100
+pub fn skip_boot(cpu: &mut Cpu, ram: &mut Ram, model: Model) {
+    // DMG and MGB leave H and C set, unless the header checksum is zero
+    let checksum = ram.cart.rom[0x14d] != 0;
+    let (af, bc, de, hl, counter) = match model {
+        Model::Dmg0 => (0x0100, 0xff13, 0x00c1, 0x8403, 0x1830),
+        Model::Dmg if checksum => (0x01b0, 0x0013, 0x00d8, 0x014d, 0xabcc),
+        Model::Dmg => (0x0180, 0x0013, 0x00d8, 0x014d, 0xabcc),
+        Model::Mgb if checksum => (0xffb0, 0x0013, 0x00d8, 0x014d, 0xabcc),
+        Model::Mgb => (0xff80, 0x0013, 0x00d8, 0x014d, 0xabcc),
+        // DIV is not documented for these
+        Model::Sgb => (0x0100, 0x0014, 0x0000, 0xc060, 0x0000),
+        Model::Sgb2 => (0xff00, 0x0014, 0x0000, 0xc060, 0x0000),
+        // A=0x11 tells games they run on a CGB, B bit 0 on a GBA
+        Model::Cgb => (0x1180, 0x0000, 0xff56, 0x000d, 0x0000),
+        Model::Agb => (0x1100, 0x0100, 0xff56, 0x000d, 0x0000),
+    };
+    cpu.reg.set_af(af);
+    cpu.reg.set_bc(bc);
+    cpu.reg.set_de(de);
+    cpu.reg.set_hl(hl);
+    cpu.reg.sp = 0xfffe;
+    cpu.reg.pc = 0x0100;
+    cpu.reg.ime = false;
+    cpu.reg.ime_delay = false;
+    cpu.halted = false;
+    cpu.wait = 0;
+
+    // where the lcd is when the boot rom jumps to the game, whether channel 1
+    // still plays the end of the logo sound (not on the SGB), and SC, the
+    // CGB boot rom leaves the internal clock selected
+    let (line, line_clock, sound, serial) = match model {
+        // only LY and the mode are documented
+        Model::Dmg0 => (145, 0, true, 0x00),
+        // LY already reads 0 at the end of line 153
+        Model::Dmg | Model::Mgb => (153, 99, true, 0x00),
+        // not documented, taken as the DMG
+        Model::Sgb | Model::Sgb2 => (153, 99, false, 0x00),
+        Model::Cgb | Model::Agb => (144, 41, true, 0x01),
+    };
+
+    for (a, v) in IO_AFTER_BOOT.iter() {
+        ram.write(*a, *v);
+    }
+    ram.write(0xff02, serial);
+    ram.set_model(model);
+    if sound {
+        ram.audio.end_boot_sound();
+    }
+    ram.video.resume_at(line, line_clock);
+    ram.timer.set_counter(counter);
+    if model.is_cgb() {
+        // a DMG cartridge runs in DMG mode, in the colors of the boot rom
+        let cgb = ram.cart.is_cgb();
+        ram.set_cgb(cgb);
+        ram.video.bg_palettes.fill(0xff);
+        if !cgb {
+            ram.video.palette = compat_palette(ram);
+        }
+    }
+    ram.interrupt.write_interrupt_request(0xe1);
+    // unmaps the boot rom
+    ram.write(0xff50, 0x01);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::cartridge::Cartridge;
+    use cpu::ram::io;
+
+    fn boot(model: Model) -> Ram {
+        let mut ram = Ram::origin(Cartridge::default());
+        skip_boot(&mut Cpu::origin(), &mut ram, model);
+        ram
+    }
+
+    // the io registers as the boot roms leave them, on the DMG (and MGB),
+    // SGB and CGB (Pan Docs, power up sequence)
+    #[rustfmt::skip]
+    const AFTER_BOOT: [(u16, u8, u8, u8); 38] = [
+        (0xff00, 0xcf, 0xcf, 0xcf), (0xff01, 0x00, 0x00, 0x00), (0xff02, 0x7e, 0x7e, 0x7f),
+        (0xff05, 0x00, 0x00, 0x00), (0xff06, 0x00, 0x00, 0x00), (0xff07, 0xf8, 0xf8, 0xf8),
+        (0xff0f, 0xe1, 0xe1, 0xe1), (0xff10, 0x80, 0x80, 0x80), (0xff11, 0xbf, 0xbf, 0xbf),
+        (0xff12, 0xf3, 0xf3, 0xf3), (0xff13, 0xff, 0xff, 0xff), (0xff14, 0xbf, 0xbf, 0xbf),
+        (0xff16, 0x3f, 0x3f, 0x3f), (0xff17, 0x00, 0x00, 0x00), (0xff18, 0xff, 0xff, 0xff),
+        (0xff19, 0xbf, 0xbf, 0xbf), (0xff1a, 0x7f, 0x7f, 0x7f), (0xff1b, 0xff, 0xff, 0xff),
+        (0xff1c, 0x9f, 0x9f, 0x9f), (0xff1d, 0xff, 0xff, 0xff), (0xff1e, 0xbf, 0xbf, 0xbf),
+        (0xff20, 0xff, 0xff, 0xff), (0xff21, 0x00, 0x00, 0x00), (0xff22, 0x00, 0x00, 0x00),
+        (0xff23, 0xbf, 0xbf, 0xbf), (0xff24, 0x77, 0x77, 0x77), (0xff25, 0xf3, 0xf3, 0xf3),
+        (0xff26, 0xf1, 0xf0, 0xf1), (0xff40, 0x91, 0x91, 0x91), (0xff41, 0x85, 0x85, 0x81),
+        (0xff42, 0x00, 0x00, 0x00), (0xff43, 0x00, 0x00, 0x00), (0xff44, 0x00, 0x00, 0x90),
+        (0xff45, 0x00, 0x00, 0x00), (0xff46, 0xff, 0xff, 0x00), (0xff47, 0xfc, 0xfc, 0xfc),
+        (0xff4a, 0x00, 0x00, 0x00), (0xff4b, 0x00, 0x00, 0x00),
+    ];
+
+    #[test]
+    fn boot_io() {
+        for (a, dmg, sgb, cgb) in AFTER_BOOT.iter() {
+            for (model, v) in [(Model::Dmg, dmg), (Model::Sgb, sgb), (Model::Cgb, cgb)].iter() {
+                assert_eq!(boot(*model).read(*a), **v, "{:04x} on {:?}", a, model);
+            }
+        }
+        assert_eq!(boot(Model::Dmg).read(0xffff), 0x00);
+        // the DMG0 boot rom ends earlier in the frame
+        let mut ram = boot(Model::Dmg0);
+        assert_eq!((ram.read(0xff44), ram.read(0xff41)), (0x91, 0x81));
+        assert_eq!(ram.read(0xff26), 0xf1);
+
+        // the lcd goes on from there, in the oam scan of line 0 16 cycles later
+        let mut ram = boot(Model::Dmg);
+        for clock in 0..16 {
+            io::Video::step(&mut ram, clock);
+        }
+        assert_eq!((ram.read(0xff44), ram.read(0xff41) & 3), (0x00, 2));
+        assert_eq!(ram.read(0xff0f), 0xe1, "no interrupt at the end of the boot");
+    }
+
+    #[test]
+    fn compat_boot() {
+        let palette = |title: &[u8], key: Option<::EmuKeys>| {
+            let mut cart = Cartridge::default();
+            cart.rom[0x134..0x134 + title.len()].copy_from_slice(title);
+            cart.rom[0x14b] = 0x01;
+            let mut ram = Ram::origin(cart);
+            if let Some(k) = key {
+                ram.joypad.press_key(k);
+            }
+            skip_boot(&mut Cpu::origin(), &mut ram, Model::Cgb);
+            ram.video.palette
+        };
+        let sml = compat::from_title(0x46, b'E').unwrap();
+        assert_eq!(palette(b"SUPER MARIOLAND", None).bg, sml.bg);
+        assert_eq!(palette(b"NOT A NINTENDO TITLE", None).bg, compat::default().bg);
+        // a combo held during the logo wins over the title
+        let left = compat::from_combo("left").unwrap();
+        assert_eq!(palette(b"SUPER MARIOLAND", Some(::EmuKeys::Left)).bg, left.bg);
+    }
+
+    #[test]
+    fn models() {
+        let mut cart = Cartridge::default();
+        assert_eq!(Model::from_header(&cart), Model::Dmg);
+        cart.rom[0x146] = 0x03;
+        cart.rom[0x14b] = 0x33;
+        assert_eq!(Model::from_header(&cart), Model::Sgb);
+        cart.rom[0x143] = 0xc0;
+        assert_eq!(Model::from_header(&cart), Model::Cgb);
+
+        let mut ram = Ram::origin(cart);
+        let mut cpu = Cpu::origin();
+        skip_boot(&mut cpu, &mut ram, Model::Agb);
+        assert_eq!((cpu.reg.a, cpu.reg.b), (0x11, 0x01));
+        assert!(ram.video.cgb);
+
+        // no STAT write interrupt on the CGB ppu, even in DMG mode
+        for (model, irq) in [(Model::Dmg, true), (Model::Cgb, false)].iter() {
+            let mut ram = boot(*model);
+            assert!(!ram.video.cgb);
+            for clock in 0..10 {
+                io::Video::step(&mut ram, clock);
+            }
+            ram.write(0xff41, 0x00);
+            let out = io::Video::step(&mut ram, 10);
+            assert_eq!(matches!(out.0, io::Interrupt::LcdcStatus), *irq, "{:?}", model);
+        }
+    }
+}
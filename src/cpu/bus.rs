@@ -0,0 +1,107 @@
+use cpu::*;
+
+// what the cpu sees of the machine, the whole address space goes through it
+pub trait Bus {
+    fn read(&mut self, a: u16) -> u8;
+    fn write(&mut self, a: u16, v: u8);
+    // what read would return, without any side effect (for debuggers and tests)
+    fn peek(&self, a: u16) -> u8;
+    // one machine cycle went by
+    fn tick(&mut self) {}
+    // the 16 bit increment unit put a on the address bus without accessing it
+    // (INC rr, DEC rr, the stack pointer), corrupts oam on the DMG
+    fn idu(&mut self, _a: u16) {}
+    // a read while the increment unit works on the same address (LD A,(HL+), POP)
+    fn read_idu(&mut self, a: u16) -> u8 {
+        self.read(a)
+    }
+
+    fn read8(&mut self, l: u8, h: u8) -> u8 {
+        let a = u8tou16(l, h);
+        self.read(a)
+    }
+    fn write8(&mut self, l: u8, h: u8, v: u8) {
+        let a = u8tou16(l, h);
+        self.write(a, v);
+    }
+    fn write88(&mut self, l: u8, h: u8, v: (u8, u8)) {
+        let a = u8tou16(l, h);
+        self.write(a, v.0);
+        self.write(a.wrapping_add(1), v.1);
+    }
+    fn push88(&mut self, sp: &mut u16, l: u8, h: u8) {
+        self.idu(*sp);
+        *sp = sp.wrapping_sub(1);
+        self.write(*sp, h);
+        *sp = sp.wrapping_sub(1);
+        self.write(*sp, l);
+    }
+    fn push16(&mut self, sp: &mut u16, v: u16) {
+        let (l, h) = u16tou8(v);
+        self.push88(sp, l, h)
+    }
+    fn pop88(&mut self, sp: &mut u16) -> (u8, u8) {
+        let l = self.read_idu(*sp);
+        *sp = sp.wrapping_add(1);
+        let h = self.read_idu(*sp);
+        *sp = sp.wrapping_add(1);
+        (l, h)
+    }
+    fn pop16(&mut self, sp: &mut u16) -> u16 {
+        let (l, h) = self.pop88(sp);
+        u8tou16(l, h)
+    }
+}
+
+#[cfg(test)]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Access {
+    Read(u16, u8),
+    Write(u16, u8),
+    Tick,
+}
+
+// forwards everything to another bus and keeps a log of it
+#[cfg(test)]
+pub struct Recorder<'a, B: Bus> {
+    pub bus: &'a mut B,
+    pub log: Vec<Access>,
+}
+
+#[cfg(test)]
+impl<'a, B: Bus> Recorder<'a, B> {
+    pub fn new(bus: &'a mut B) -> Recorder<'a, B> {
+        Recorder {
+            bus,
+            log: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl<'a, B: Bus> Bus for Recorder<'a, B> {
+    fn read(&mut self, a: u16) -> u8 {
+        let v = self.bus.read(a);
+        self.log.push(Access::Read(a, v));
+        v
+    }
+    fn write(&mut self, a: u16, v: u8) {
+        self.bus.write(a, v);
+        self.log.push(Access::Write(a, v));
+    }
+    fn peek(&self, a: u16) -> u8 {
+        self.bus.peek(a)
+    }
+    fn tick(&mut self) {
+        self.bus.tick();
+        self.log.push(Access::Tick);
+    }
+    fn idu(&mut self, a: u16) {
+        self.bus.idu(a);
+    }
+    fn read_idu(&mut self, a: u16) -> u8 {
+        let v = self.bus.read_idu(a);
+        self.log.push(Access::Read(a, v));
+        v
+    }
+}
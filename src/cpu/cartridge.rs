@@ -242,6 +242,9 @@ impl Cartridge {
         self.romswitch[self.cur_rom][a as usize]
     }
     pub fn read_ramswitch(&self, a: u16) -> u8 {
+        if self.ramswitch.is_empty() {
+            return 0xff;
+        }
         self.ramswitch[self.cur_ram][a as usize]
     }
     pub fn write_ramswitch(&mut self, a: u16, v: u8) {
@@ -249,8 +252,29 @@ impl Cartridge {
         if self.ramswitch.len() == 0{ return};
         self.ramswitch[self.cur_ram][a as usize] = v;
     }
+    // the SGB functions need the new licensee code too
+    pub fn is_sgb(&self) -> bool {
+        self.rom[0x146] == 0x03 && self.rom[0x14b] == 0x33
+    }
     pub fn is_cgb(&self) -> bool {
-        self.rom[0x143] == 0x80
+        // 0x80 also runs on the DMG, 0xc0 only on the CGB
+        self.rom[0x143] & 0x80 != 0
+    }
+    // the CGB boot rom looks up the palette of Nintendo games by the sum of
+    // their title bytes, and the 4th letter when titles share a sum
+    pub fn compat_hash(&self) -> Option<(u8, u8)> {
+        let nintendo = match self.rom[0x14b] {
+            0x01 => true,
+            0x33 => &self.rom[0x144..0x146] == b"01",
+            _ => false,
+        };
+        if !nintendo {
+            return None;
+        }
+        let hash = self.rom[0x134..0x144]
+            .iter()
+            .fold(0u8, |h, v| h.wrapping_add(*v));
+        Some((hash, self.rom[0x137]))
     }
     pub fn get_rom_bank_count(&self) -> u16 {
         match self.rom[0x148] {
@@ -289,6 +313,9 @@ impl Cartridge {
         } else {
             println!("In not CGB");
         }
+        if let Some((hash, letter)) = self.compat_hash() {
+            println!("CGB compatibility hash {:02x} {}", hash, letter as char);
+        }
         println!("Old licensee code {:02x}", self.rom[0x14b]);
         println!(
             "New licensee code {:02x}{:02x}",
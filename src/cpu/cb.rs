@@ -2,7 +2,7 @@
 
 use cpu::*;
 
-pub fn instr_cb(ram: &mut Ram, reg: &mut Registers, alu: &mut Alu, op: u8) -> CpuState {
+pub fn instr_cb<B: Bus>(ram: &mut B, reg: &mut Registers, op: u8) -> CpuState {
     let op_reg = op & 0x7;
     let op_op = (op & 0xc0) >> 6;
     let op_bit = (op & 0x38) >> 3;
@@ -14,7 +14,7 @@ pub fn instr_cb(ram: &mut Ram, reg: &mut Registers, alu: &mut Alu, op: u8) -> Cp
         3 => reg.e,
         4 => reg.h,
         5 => reg.l,
-        6 => ram.read8(reg.l, reg.h),
+        6 => ram.read(reg.hl()),
         7 => reg.a,
         _ => panic!("impossible"),
     };
@@ -24,41 +24,41 @@ pub fn instr_cb(ram: &mut Ram, reg: &mut Registers, alu: &mut Alu, op: u8) -> Cp
             match op_bit {
                 // RLC
                 0 => {
-                    alu.flag_carry = val & 0x80 != 0;
+                    reg.flags.flag_carry = val & 0x80 != 0;
                     val = val.rotate_left(1);
                 }
                 // RRC
                 1 => {
-                    alu.flag_carry = val & 1 != 0;
+                    reg.flags.flag_carry = val & 1 != 0;
                     val = val.rotate_right(1);
                 }
                 // RL
                 2 => {
                     let c = val & 0x80 != 0;
                     val = val.wrapping_shl(1);
-                    if alu.flag_carry {
+                    if reg.flags.flag_carry {
                         val |= 1;
                     }
-                    alu.flag_carry = c;
+                    reg.flags.flag_carry = c;
                 }
                 // RR
                 3 => {
                     let c = val & 1 != 0;
                     val = val.wrapping_shr(1);
-                    if alu.flag_carry {
+                    if reg.flags.flag_carry {
                         val |= 0x80;
                     }
-                    alu.flag_carry = c;
+                    reg.flags.flag_carry = c;
                 }
                 // SLA
                 4 => {
-                    alu.flag_carry = val & 0x80 != 0;
+                    reg.flags.flag_carry = val & 0x80 != 0;
                     val = val.wrapping_shl(1);
                 }
                 // SRA
                 5 => {
                     let c = val & 0x80 != 0;
-                    alu.flag_carry = val & 1 != 0;
+                    reg.flags.flag_carry = val & 1 != 0;
                     val = val.wrapping_shr(1);
                     if c {
                         val |= 0x80;
@@ -69,24 +69,24 @@ pub fn instr_cb(ram: &mut Ram, reg: &mut Registers, alu: &mut Alu, op: u8) -> Cp
                     let h = (val & 0xf0) >> 4;
                     let l = (val & 0xf) << 4;
                     val = h + l;
-                    alu.flag_carry = false;
+                    reg.flags.flag_carry = false;
                 }
                 // SRL
                 7 => {
-                    alu.flag_carry = val & 1 != 0;
+                    reg.flags.flag_carry = val & 1 != 0;
                     val = val.wrapping_shr(1);
                 }
                 _ => panic!("impossible"),
             }
-            alu.flag_zero = val == 0;
-            alu.flag_substract = false;
-            alu.flag_halfcarry = false;
+            reg.flags.flag_zero = val == 0;
+            reg.flags.flag_substract = false;
+            reg.flags.flag_halfcarry = false;
         }
         // BIT
         1 => {
-            alu.flag_substract = false;
-            alu.flag_halfcarry = true;
-            alu.flag_zero = val & bit_mask == 0;
+            reg.flags.flag_substract = false;
+            reg.flags.flag_halfcarry = true;
+            reg.flags.flag_zero = val & bit_mask == 0;
         }
         // RES
         2 => {
@@ -124,7 +124,7 @@ pub fn instr_cb(ram: &mut Ram, reg: &mut Registers, alu: &mut Alu, op: u8) -> Cp
             CpuState::Wait(1)
         }
         6 => {
-            ram.write8(reg.l, reg.h, val);
+            ram.write(reg.hl(), val);
             CpuState::Wait(3)
         }
         7 => {
@@ -0,0 +1,263 @@
+// Runs the per opcode json test vectors (initial state, final state and bus
+// activity of every cycle) found in test-roms/sm83/ against the cpu alone,
+// on a flat 64KB memory, and the serialization of the cpu state.
+
+extern crate serde_json;
+
+use self::serde_json::Value;
+use std::fs;
+
+use cpu::bus::{Access, Recorder};
+use cpu::*;
+use test_roms::test_dir;
+
+struct FlatBus {
+    mem: Vec<u8>,
+}
+
+impl Bus for FlatBus {
+    fn read(&mut self, a: u16) -> u8 {
+        self.mem[a as usize]
+    }
+    fn write(&mut self, a: u16, v: u8) {
+        self.mem[a as usize] = v;
+    }
+    fn peek(&self, a: u16) -> u8 {
+        self.mem[a as usize]
+    }
+}
+
+fn field(state: &Value, name: &str) -> u16 {
+    state[name].as_u64().unwrap_or(0) as u16
+}
+
+fn ram(state: &Value) -> Vec<(u16, u8)> {
+    state["ram"]
+        .as_array()
+        .map(|l| {
+            l.iter()
+                .map(|e| (e[0].as_u64().unwrap() as u16, e[1].as_u64().unwrap() as u8))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn load(state: &Value, reg: &mut Registers, bus: &mut FlatBus) {
+    reg.a = field(state, "a") as u8;
+    reg.b = field(state, "b") as u8;
+    reg.c = field(state, "c") as u8;
+    reg.d = field(state, "d") as u8;
+    reg.e = field(state, "e") as u8;
+    reg.h = field(state, "h") as u8;
+    reg.l = field(state, "l") as u8;
+    reg.sp = field(state, "sp");
+    reg.pc = field(state, "pc");
+    reg.ime = field(state, "ime") != 0;
+    reg.ime_delay = false;
+    reg.flags.set_bits(field(state, "f") as u8);
+    for (a, v) in ram(state) {
+        bus.mem[a as usize] = v;
+    }
+}
+
+fn compare(state: &Value, reg: &Registers, bus: &FlatBus) -> Vec<String> {
+    let mut out = Vec::new();
+    let regs = [
+        ("a", reg.a as u16),
+        ("b", reg.b as u16),
+        ("c", reg.c as u16),
+        ("d", reg.d as u16),
+        ("e", reg.e as u16),
+        ("h", reg.h as u16),
+        ("l", reg.l as u16),
+        ("sp", reg.sp),
+        ("pc", reg.pc),
+    ];
+    for (name, v) in regs.iter() {
+        if field(state, name) != *v {
+            out.push(format!(
+                "{} is {:02x} expected {:02x}",
+                name,
+                v,
+                field(state, name)
+            ));
+        }
+    }
+    let f = field(state, "f") as u8;
+    if f != reg.flags.bits() {
+        let mut expected = Flags::origin();
+        expected.set_bits(f);
+        out.push(format!("flags are {} expected {}", reg.flags, expected));
+    }
+    if !state["ime"].is_null() && (field(state, "ime") != 0) != reg.ime {
+        out.push(format!("ime is {} expected {}", reg.ime, !reg.ime));
+    }
+    for (a, v) in ram(state) {
+        if bus.mem[a as usize] != v {
+            out.push(format!(
+                "({:04x}) is {:02x} expected {:02x}",
+                a, bus.mem[a as usize], v
+            ));
+        }
+    }
+    out
+}
+
+fn compare_cycles(test: &Value, log: &[Access]) -> Vec<String> {
+    let mut out = Vec::new();
+    let cycles = test["cycles"].as_array().cloned().unwrap_or_default();
+    let ran = log.iter().filter(|a| **a == Access::Tick).count();
+    if ran != cycles.len() {
+        out.push(format!("took {} cycles expected {}", ran, cycles.len()));
+    }
+    // idle cycles are null or have no r/w pin set
+    let expected: Vec<Access> = cycles
+        .iter()
+        .filter_map(|c| {
+            let pins = c[2].as_str()?;
+            if pins.contains('r') || pins.contains('w') {
+                let (a, v) = (c[0].as_u64()? as u16, c[1].as_u64()? as u8);
+                if pins.contains('w') {
+                    Some(Access::Write(a, v))
+                } else {
+                    Some(Access::Read(a, v))
+                }
+            } else {
+                None
+            }
+        })
+        .collect();
+    let done: Vec<Access> = log
+        .iter()
+        .filter(|a| **a != Access::Tick)
+        .cloned()
+        .collect();
+    if expected != done {
+        let show = |l: &[Access]| {
+            l.iter()
+                .map(|a| match a {
+                    Access::Read(a, v) => format!("r{:04x}:{:02x}", a, v),
+                    Access::Write(a, v) => format!("w{:04x}:{:02x}", a, v),
+                    Access::Tick => String::new(),
+                })
+                .collect::<Vec<String>>()
+                .join(" ")
+        };
+        out.push(format!(
+            "bus did [{}] expected [{}]",
+            show(&done),
+            show(&expected)
+        ));
+    }
+    out
+}
+
+// returns the mismatches of the first failing test, and the failed count
+fn run_file(tests: &[Value]) -> (usize, Option<(String, Vec<String>)>) {
+    let mut bus = FlatBus {
+        mem: vec![0; 0x10000],
+    };
+    let mut failed = 0;
+    let mut first = None;
+    for test in tests {
+        let mut reg = Registers::origin();
+        load(&test["initial"], &mut reg, &mut bus);
+
+        let mut recorder = Recorder::new(&mut bus);
+        instruct(&mut recorder, &mut reg);
+        let log = recorder.log;
+
+        let mut errors = compare(&test["final"], &reg, &bus);
+        errors.extend(compare_cycles(test, &log));
+        if !errors.is_empty() {
+            failed += 1;
+            if first.is_none() {
+                let name = test["name"].as_str().unwrap_or("?").to_string();
+                first = Some((name, errors));
+            }
+        }
+
+        for (a, _) in ram(&test["initial"])
+            .iter()
+            .chain(ram(&test["final"]).iter())
+        {
+            bus.mem[*a as usize] = 0;
+        }
+        for access in log {
+            if let Access::Write(a, _) = access {
+                bus.mem[a as usize] = 0;
+            }
+        }
+    }
+    (failed, first)
+}
+
+#[test]
+fn sm83_json() {
+    let dir = match test_dir("sm83") {
+        Some(d) => d,
+        None => return,
+    };
+    let mut files: Vec<_> = fs::read_dir(&dir)
+        .expect("can not list test vectors")
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.extension().and_then(|x| x.to_str()) == Some("json"))
+        .collect();
+    files.sort();
+
+    let mut total_failed = 0;
+    let mut opcodes_failed = 0;
+    for f in files.iter() {
+        let text = fs::read_to_string(f).expect("can not read test vectors");
+        let tests: Vec<Value> = serde_json::from_str(&text).expect("invalid test vectors");
+        let opcode = f.file_stem().unwrap().to_string_lossy();
+        let (failed, first) = run_file(&tests);
+        match first {
+            None => println!("{:<6} ok     {}", opcode, tests.len()),
+            Some((name, errors)) => {
+                println!(
+                    "{:<6} FAILED {}/{} first is {}",
+                    opcode,
+                    failed,
+                    tests.len(),
+                    name
+                );
+                for e in errors {
+                    println!("           {}", e);
+                }
+                opcodes_failed += 1;
+            }
+        }
+        total_failed += failed;
+    }
+    println!(
+        "{} of {} opcodes failed, {} tests",
+        opcodes_failed,
+        files.len(),
+        total_failed
+    );
+    assert!(total_failed == 0, "{} opcodes failed", opcodes_failed);
+}
+
+#[test]
+fn cpu_serde() {
+    let mut cpu = Cpu::origin();
+    cpu.reg.set_af(0x11b0);
+    cpu.reg.set_bc(0x0013);
+    cpu.reg.set_de(0x00d8);
+    cpu.reg.set_hl(0x014d);
+    cpu.reg.sp = 0xfffe;
+    cpu.reg.pc = 0x0150;
+    cpu.reg.ime = true;
+    cpu.halted = true;
+    cpu.wait = 2;
+    cpu.cycles = 123456;
+
+    let text = serde_json::to_string(&cpu).unwrap();
+    let back: Cpu = serde_json::from_str(&text).unwrap();
+    assert_eq!(back.to_string(), cpu.to_string());
+    assert_eq!(back.reg.af(), 0x11b0);
+    assert_eq!(back.wait, 2);
+    assert_eq!(back.cycles, 123456);
+}
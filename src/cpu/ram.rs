@@ -20,28 +20,92 @@ pub static DMG: [u8; 0x100] = [
     0xF5, 0x06, 0x19, 0x78, 0x86, 0x23, 0x05, 0x20, 0xFB, 0x86, 0x20, 0xFE, 0x3E, 0x01, 0xE0, 0x50,
 ];
 
+// bits of the io registers that always read as 1, 0xff for the unmapped and write only ones
+#[rustfmt::skip]
+const IO_READ_MASK: [u8; 0x80] = [
+    // P1   SB    SC                DIV   TIMA  TMA   TAC                                         IF
+    0xc0, 0x00, 0x7e, 0xff, 0x00, 0x00, 0x00, 0xf8, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xe0,
+    // NR10 NR11  NR12  NR13  NR14        NR21  NR22  NR23  NR24  NR30  NR31  NR32  NR33  NR34
+    0x80, 0x3f, 0x00, 0xff, 0xbf, 0xff, 0x3f, 0x00, 0xff, 0xbf, 0x7f, 0xff, 0x9f, 0xff, 0xbf, 0xff,
+    // NR41 NR42  NR43  NR44  NR50  NR51  NR52
+    0xff, 0x00, 0x00, 0xbf, 0x00, 0x00, 0x70, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    // wave ram
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    // LCDC STAT  SCY   SCX   LY    LYC   DMA   BGP   OBP0  OBP1  WY    WX
+    0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff,
+    // boot lock
+    0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+];
+
 pub struct Ram {
     pub interrupt: io::InterruptManager,
     pub joypad: io::Joypad,
-    serial: io::Serial,
+    pub serial: io::Serial,
     dma: io::Dma,
-    timer: io::Timer,
+    hdma: io::Hdma,
+    pub timer: io::Timer,
     pub video: io::Video,
+    pub sgb: io::Sgb,
     pub audio: io::Audio,
 
-    pub ram: [u8; 0x2000],
+    // 8 banks of 4KB on the CGB, SVBK selects the one at 0xd000
+    pub ram: [u8; 0x8000],
+    wram_bank: usize,
     pub cart: cartridge::Cartridge,
     pub rom: [u8; 0x4000],
     pub romswitch: [u8; 0x4000],
     pub ramswitch: [u8; 0x2000],
     pub hram: [u8; 0x7f],
-    oam: [u8; 0xa0],
     booting: bool,
+    // mapped over the cartridge until 0xff50 is written
+    boot_rom: Vec<u8>,
+    // KEY0, written by the CGB boot rom, 0x04 runs the cartridge in DMG mode
+    key0: u8,
+    // KEY1 bit 0, the next STOP switches the speed
+    speed_switch: bool,
+    pub double_speed: bool,
+    // cycles the cpu is stopped for by vram DMA
+    pub stall: u32,
     pub cur_ram: u8,
     pub cur_rom: u8,
 }
 
 impl Ram {
+    pub fn set_boot_rom(&mut self, rom: Vec<u8>) {
+        self.set_cgb(rom.len() == boot::CGB_BOOT_ROM);
+        self.boot_rom = rom;
+    }
+    // the hardware differences that do not depend on the cartridge
+    pub fn set_model(&mut self, model: boot::Model) {
+        self.video.cgb_hardware = model.is_cgb();
+        self.audio.cgb_hardware = model.is_cgb();
+        // DMA reads 0xff at power on, 0x00 on the CGB
+        self.dma.address = if model.is_cgb() { 0x00 } else { 0xff };
+        self.sgb.enabled = model.is_sgb() && self.cart.is_sgb();
+    }
+    // turns on the CGB registers and video
+    pub fn set_cgb(&mut self, cgb: bool) {
+        self.video.cgb = cgb;
+    }
+    // called on STOP, switches the speed if KEY1 asked for it
+    pub fn switch_speed(&mut self) -> bool {
+        if !self.speed_switch {
+            return false;
+        }
+        self.speed_switch = false;
+        self.double_speed = !self.double_speed;
+        self.timer.write_div(0);
+        true
+    }
+    // index in `ram` of the internal ram at 0xc000 + a
+    fn wram_index(&self, a: u16) -> usize {
+        match a {
+            0x0000..=0x0fff => a as usize,
+            _ => self.wram_bank * 0x1000 + (a - 0x1000) as usize,
+        }
+    }
     pub fn origin(cart: cartridge::Cartridge) -> Ram {
         Ram {
             interrupt: io::InterruptManager::origin(),
@@ -49,18 +113,25 @@ impl Ram {
             joypad: io::Joypad::origin(),
             serial: io::Serial::origin(),
             dma: io::Dma::origin(),
+            hdma: io::Hdma::origin(),
             timer: io::Timer::origin(),
             video: io::Video::origin(),
+            sgb: io::Sgb::origin(),
             audio: io::Audio::origin(),
 
-            ram: [0; 0x2000],
+            ram: [0; 0x8000],
+            wram_bank: 1,
             cart,
             rom: [0; 0x4000],
             romswitch: [0; 0x4000],
             ramswitch: [0; 0x2000],
             hram: [0; 0x7f],
-            oam: [0; 0xa0],
             booting: true,
+            boot_rom: DMG.to_vec(),
+            key0: 0,
+            speed_switch: false,
+            double_speed: false,
+            stall: 0,
             cur_ram: 0,
             cur_rom: 1,
         }
@@ -93,8 +164,17 @@ impl Ram {
       */
 
     pub fn read_io(&self, a: u16) -> u8 {
-        match a {
-            0x00 => self.joypad.read(),
+        let mask = match a {
+            // the CGB registers have no unused bits to mask
+            0x4d | 0x4f | 0x55 | 0x68..=0x6c | 0x70 if self.video.cgb => 0,
+            _ => IO_READ_MASK[a as usize],
+        };
+        self.peek_io(a).unwrap_or(0xff) | mask
+    }
+    // None for the unmapped registers
+    pub fn peek_io(&self, a: u16) -> Option<u8> {
+        let v = match a {
+            0x00 => self.sgb.read_joypad(self.joypad.read()),
             0x01 => self.serial.read_data(),
             0x02 => self.serial.read_control(),
             0x04 => self.timer.read_div(),
@@ -118,16 +198,29 @@ impl Ram {
             0x49 => self.video.read_sprite_palette_1(),
             0x4a => self.video.read_window_scroll_y(),
             0x4b => self.video.read_window_scroll_x(),
-            _ => {
-                println!("reading from unimplemented io {:02x}", a);
-                0xff
+            0x50 => !self.booting as u8,
+            0x4d if self.video.cgb => {
+                0x7e | (self.double_speed as u8) << 7 | self.speed_switch as u8
             }
-        }
+            0x4f if self.video.cgb => self.video.read_vram_bank(),
+            0x55 if self.video.cgb => self.hdma.read_control(),
+            0x68 if self.video.cgb => self.video.bg_palettes.read_index(),
+            0x69 if self.video.cgb => self.video.read_bg_palette_data(),
+            0x6a if self.video.cgb => self.video.obj_palettes.read_index(),
+            0x6b if self.video.cgb => self.video.read_obj_palette_data(),
+            0x6c if self.video.cgb => self.video.read_object_priority(),
+            0x70 if self.video.cgb => 0xf8 | self.wram_bank as u8,
+            _ => return None,
+        };
+        Some(v)
     }
     pub fn write_io(&mut self, a: u16, v: u8) {
         println!("write io {} {}",a,v );
         match a {
-            0x00 => self.joypad.write(v),
+            0x00 => {
+                self.joypad.write(v);
+                self.sgb.write_joypad(v);
+            }
             0x01 => self.serial.write_data(v),
             0x02 => self.serial.write_control(v),
             0x04 => self.timer.write_div(v),
@@ -150,20 +243,74 @@ impl Ram {
             0x49 => self.video.write_sprite_palette_1(v),
             0x4a => self.video.write_window_scroll_y(v),
             0x4b => self.video.write_window_scroll_x(v),
+            // only the boot rom can write KEY0
+            0x4c if self.booting => self.key0 = v,
+            0x4d if self.video.cgb => self.speed_switch = v & 1 != 0,
+            0x4f if self.video.cgb => self.video.write_vram_bank(v),
+            0x51 if self.video.cgb => self.hdma.write_source_high(v),
+            0x52 if self.video.cgb => self.hdma.write_source_low(v),
+            0x53 if self.video.cgb => self.hdma.write_dest_high(v),
+            0x54 if self.video.cgb => self.hdma.write_dest_low(v),
+            0x55 if self.video.cgb => io::Hdma::write_control(self, v),
+            0x68 if self.video.cgb => self.video.bg_palettes.write_index(v),
+            0x69 if self.video.cgb => self.video.write_bg_palette_data(v),
+            0x6a if self.video.cgb => self.video.obj_palettes.write_index(v),
+            0x6b if self.video.cgb => self.video.write_obj_palette_data(v),
+            // OPRI is locked after the boot too
+            0x6c if self.video.cgb && self.booting => self.video.write_object_priority(v),
+            // bank 0 maps bank 1
+            0x70 if self.video.cgb => self.wram_bank = ((v & 7) as usize).max(1),
 
             _ => println!("writing {:02x} to unimplemented io {:02x}", v, a),
         }
     }
-    pub fn read(&self, a: u16) -> u8 {
+}
+
+impl Bus for Ram {
+    fn read(&mut self, a: u16) -> u8 {
+        if self.dma.blocks(a) {
+            return if (0xfe00..=0xfeff).contains(&a) {
+                0xff
+            } else {
+                self.dma.byte
+            };
+        }
+        match a {
+            0x8000..=0x9fff if self.video.vram_blocked() => 0xff,
+            0xfe00..=0xfeff if self.video.oam_blocked() => {
+                self.video.oam_bug_read();
+                0xff
+            }
+            0xfea0..=0xfeff => {
+                println!("should not read there {:04x} ", a);
+                0xff
+            }
+            _ => self.peek(a),
+        }
+    }
+
+    fn idu(&mut self, a: u16) {
+        if (0xfe00..=0xfeff).contains(&a) && !self.dma.blocks(a) {
+            self.video.oam_bug_write();
+        }
+    }
+
+    fn read_idu(&mut self, a: u16) -> u8 {
+        // one corruption mixing the read and the increase, not the read one again
+        if (0xfe00..=0xfeff).contains(&a) && !self.dma.blocks(a) && self.video.oam_blocked() {
+            self.video.oam_bug_read_increase();
+            return 0xff;
+        }
+        self.read(a)
+    }
+
+    fn peek(&self, a: u16) -> u8 {
         match a {
-            0x0000..=0x00ff =>
-            //ROM #0 or DMG
+            0x0000..=0x00ff | 0x0200..=0x08ff
+                if self.booting && (a as usize) < self.boot_rom.len() =>
+            //boot rom
             {
-                if self.booting {
-                    DMG[a as usize]
-                } else {
-                    self.cart.rom[a as usize]
-                }
+                self.boot_rom[a as usize]
             }
             0x0000..=0x3fff =>
             //ROM #0
@@ -188,19 +335,19 @@ impl Ram {
             0xc000..=0xdfff =>
             //RAM INTERN
             {
-                self.ram[(a - 0xc000) as usize]
+                self.ram[self.wram_index(a - 0xc000)]
             }
             0xe000..=0xfdff =>
             //RAM INTERN EC
             {
-                self.ram[(a - 0xe000) as usize]
+                self.ram[self.wram_index(a - 0xe000)]
             }
             0xfe00..=0xfe9f =>
             //OAM
             {
-                self.oam[(a - 0xfe00) as usize]
+                self.video.read_oam(a - 0xfe00)
             }
-            0xff00..=0xff4b =>
+            0xff00..=0xff7f =>
             //IO
             {
                 self.read_io(a - 0xff00)
@@ -215,16 +362,26 @@ impl Ram {
             {
                 self.interrupt.read_interrupt_enable()
             }
-            0xfea0..=0xfeff | 0xff4c..=0xff7f =>
-            // empty, no IO
+            0xfea0..=0xfeff =>
+            // empty
             {
-                println!("should not read there {:04x} ", a);
                 0xff
             }
         }
     }
 
-    pub fn write(&mut self, a: u16, v: u8) {
+    fn write(&mut self, a: u16, v: u8) {
+        if self.dma.blocks(a) {
+            return;
+        }
+        match a {
+            0x8000..=0x9fff if self.video.vram_blocked() => return,
+            0xfe00..=0xfeff if self.video.oam_blocked() => {
+                self.video.oam_bug_write();
+                return;
+            }
+            _ => {}
+        }
         match a {
             0x0000..=0x1fff =>
             //ram enable
@@ -253,22 +410,18 @@ impl Ram {
             {
                 self.cart.write_ramswitch(a - 0xa000, v)
             }
-            0xc000..=0xdfff =>
-            //RAM INTERN
+            0xc000..=0xfdff =>
+            //RAM INTERN and its echo
             {
-                self.ram[(a % 0x2000) as usize] = v
-            }
-            0xe000..=0xfdff =>
-            //RAM INTERN EC
-            {
-                self.ram[(a % 0x2000) as usize] = v
+                let i = self.wram_index(a % 0x2000);
+                self.ram[i] = v
             }
             0xfe00..=0xfe9f =>
             //OAM
             {
                 self.video.write_oam(a - 0xfe00, v)
             }
-            0xff00..=0xff4b =>
+            0xff00..=0xff4f | 0xff51..=0xff55 | 0xff68..=0xff6c | 0xff70 =>
             //IO
             {
                 self.write_io(a - 0xff00, v)
@@ -287,9 +440,12 @@ impl Ram {
             0xff50 =>
             // boot end
             {
+                if self.booting && self.key0 & 0x04 != 0 {
+                    self.set_cgb(false);
+                }
                 self.booting = false;
             }
-            0xfea0..=0xfeff | 0xff4c..=0xff4f | 0xff51..=0xff7f =>
+            0xfea0..=0xfeff | 0xff56..=0xff67 | 0xff6d..=0xff6f | 0xff71..=0xff7f =>
             // empty, no IO
             {
                 //println!("should not write there {:04x} {:02x}",a,v);
@@ -297,36 +453,238 @@ impl Ram {
         }
         //println!("wrote {:02x}:{} at {:04x}",v,v as char,a);
     }
-    pub fn read8(&mut self, l: u8, h: u8) -> u8 {
-        let a = u8tou16(l, h);
-        self.read(a)
-    }
-    pub fn write8(&mut self, l: u8, h: u8, v: u8) {
-        let a = u8tou16(l, h);
-        self.write(a, v);
-    }
-    pub fn write88(&mut self, l: u8, h: u8, v: (u8, u8)) {
-        let a = u8tou16(l, h);
-        self.write(a, v.0);
-        self.write(a + 1, v.1);
-    }
-    pub fn push88(&mut self, sp: &mut u16, l: u8, h: u8) {
-        *sp -= 2;
-        self.write(*sp, l);
-        self.write(*sp + 1, h);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // what each io register reads back after 0x00 was written to it, None for
+    // the ones with side effects checked apart
+    #[rustfmt::skip]
+    const AFTER_ZERO: [Option<u8>; 0x80] = [
+        None, Some(0x00), Some(0x7e), Some(0xff), None, Some(0x00), Some(0x00), Some(0xf8),
+        Some(0xff), Some(0xff), Some(0xff), Some(0xff), Some(0xff), Some(0xff), Some(0xff), Some(0xe0),
+        Some(0x80), Some(0x3f), Some(0x00), Some(0xff), Some(0xbf), Some(0xff), Some(0x3f), Some(0x00),
+        Some(0xff), Some(0xbf), Some(0x7f), Some(0xff), Some(0x9f), Some(0xff), Some(0xbf), Some(0xff),
+        Some(0xff), Some(0x00), Some(0x00), Some(0xbf), Some(0x00), Some(0x00), None, Some(0xff),
+        Some(0xff), Some(0xff), Some(0xff), Some(0xff), Some(0xff), Some(0xff), Some(0xff), Some(0xff),
+        Some(0x00), Some(0x00), Some(0x00), Some(0x00), Some(0x00), Some(0x00), Some(0x00), Some(0x00),
+        Some(0x00), Some(0x00), Some(0x00), Some(0x00), Some(0x00), Some(0x00), Some(0x00), Some(0x00),
+        Some(0x00), None, Some(0x00), Some(0x00), None, Some(0x00), None, Some(0x00),
+        Some(0x00), Some(0x00), Some(0x00), Some(0x00), Some(0xff), Some(0xff), Some(0xff), Some(0xff),
+        None, Some(0xff), Some(0xff), Some(0xff), Some(0xff), Some(0xff), Some(0xff), Some(0xff),
+        Some(0xff), Some(0xff), Some(0xff), Some(0xff), Some(0xff), Some(0xff), Some(0xff), Some(0xff),
+        Some(0xff), Some(0xff), Some(0xff), Some(0xff), Some(0xff), Some(0xff), Some(0xff), Some(0xff),
+        Some(0xff), Some(0xff), Some(0xff), Some(0xff), Some(0xff), Some(0xff), Some(0xff), Some(0xff),
+        Some(0xff), Some(0xff), Some(0xff), Some(0xff), Some(0xff), Some(0xff), Some(0xff), Some(0xff),
+        Some(0xff), Some(0xff), Some(0xff), Some(0xff), Some(0xff), Some(0xff), Some(0xff), Some(0xff),
+    ];
+
+    #[test]
+    fn io_read_back() {
+        let mut ram = Ram::origin(cartridge::Cartridge::default());
+        // sound registers can only be written while powered
+        ram.write(0xff26, 0x80);
+        for a in 0xff00..=0xff7f {
+            if let Some(expected) = AFTER_ZERO[(a - 0xff00) as usize] {
+                ram.write(a, 0x00);
+                assert_eq!(ram.read(a), expected, "{:04x} after 0x00", a);
+                ram.write(a, 0xff);
+                assert_eq!(ram.read(a), 0xff, "{:04x} after 0xff", a);
+            }
+        }
+
+        ram.write(0xff00, 0x30);
+        assert_eq!(ram.read(0xff00), 0xff, "no line selected");
+        ram.write(0xff00, 0x10);
+        assert_eq!(ram.read(0xff00), 0xdf, "buttons selected, none pressed");
+
+        ram.write(0xff04, 0x42);
+        assert_eq!(ram.read(0xff04), 0x00, "DIV resets on write");
+
+        assert_eq!(ram.read(0xff41) & 0x80, 0x80, "STAT bit 7");
+        ram.write(0xff41, 0x00);
+        assert_eq!(ram.read(0xff41) & 0xf8, 0x80, "STAT interrupt bits cleared");
+
+        ram.write(0xff44, 0x42);
+        assert_eq!(ram.read(0xff44), 0x00, "LY is read only");
+
+        ram.write(0xff26, 0x00);
+        assert_eq!(ram.read(0xff26), 0x70, "sound off");
+
+        assert_eq!(ram.read(0xff50), 0xfe, "boot rom mapped");
+        ram.write(0xff50, 0x01);
+        assert_eq!(ram.read(0xff50), 0xff, "boot rom unmapped");
     }
-    pub fn push16(&mut self, sp: &mut u16, v: u16) {
-        let (l, h) = u16tou8(v);
-        self.push88(sp, l, h)
+
+    #[test]
+    fn vram_oam_blocking() {
+        let mut ram = Ram::origin(cartridge::Cartridge::default());
+        ram.write(0x8000, 0x12);
+        ram.write(0xfe00, 0x34);
+        ram.write(0xff40, 0x80);
+        let mut clock = 0;
+        let mut mode = |ram: &mut Ram, m: u8| {
+            while ram.video.get_video_mode() != m {
+                clock += 1;
+                io::Video::step(ram, clock);
+            }
+        };
+
+        mode(&mut ram, 2);
+        assert_eq!(ram.read(0x8000), 0x12, "vram in mode 2");
+        assert_eq!(ram.read(0xfe00), 0xff, "oam in mode 2");
+        ram.write(0xfe00, 0x56);
+
+        mode(&mut ram, 3);
+        assert_eq!(ram.read(0x8000), 0xff, "vram in mode 3");
+        assert_eq!(ram.read(0xfe00), 0xff, "oam in mode 3");
+        ram.write(0x8000, 0x78);
+
+        mode(&mut ram, 0);
+        assert_eq!(ram.read(0x8000), 0x12, "vram write in mode 3 is lost");
+        assert_eq!(ram.read(0xfe00), 0x34, "oam write in mode 2 is lost");
     }
-    pub fn pop88(&mut self, sp: &mut u16) -> (u8, u8) {
-        let l = self.read(*sp);
-        let h = self.read(*sp + 1);
-        *sp += 2;
-        (l, h)
+
+    #[test]
+    fn oam_bug() {
+        let mut ram = Ram::origin(cartridge::Cartridge::default());
+        for a in 0xfe00..0xfea0 {
+            ram.write(a, a as u8);
+        }
+        ram.write(0xff40, 0x80);
+        let mut clock = 0;
+        while ram.video.get_video_mode() != 2 {
+            clock += 1;
+            io::Video::step(&mut ram, clock);
+        }
+        // the ppu is on row 0, nothing happens
+        ram.idu(0xfe00);
+        for a in 0xfe00..0xfea0 {
+            assert_eq!(ram.peek(a), a as u8);
+        }
+        for _ in 0..4 {
+            clock += 1;
+            io::Video::step(&mut ram, clock);
+        }
+        // row 4, its first word mixes with the first and third of row 3
+        let word = |ram: &Ram, a: u16| ram.peek(a) as u16 | (ram.peek(a + 1) as u16) << 8;
+        let (a, b, c) = (word(&ram, 0xfe20), word(&ram, 0xfe18), word(&ram, 0xfe1c));
+        ram.idu(0xfe42);
+        assert_eq!(word(&ram, 0xfe20), ((a ^ c) & (b ^ c)) ^ c);
+        for i in 2..8 {
+            assert_eq!(ram.peek(0xfe20 + i), ram.peek(0xfe18 + i));
+        }
+        // other rows untouched
+        assert_eq!(ram.peek(0xfe28), 0x28);
+        assert_eq!(ram.peek(0xfe17), 0x17);
+
+        // LD A,(HL+) on row 4: the previous row mixes with the two around it
+        // and is copied over them
+        for a in 0..0xa0 {
+            ram.video.write_oam(a, (a as u8).wrapping_mul(37) ^ 0x5a);
+        }
+        let (a, b) = (word(&ram, 0xfe10), word(&ram, 0xfe18));
+        let (c, d) = (word(&ram, 0xfe20), word(&ram, 0xfe14));
+        let row3: Vec<u8> = (2..8).map(|i| ram.peek(0xfe18 + i)).collect();
+        assert_eq!(ram.read_idu(0xfe42), 0xff);
+        let mixed = (b & (a | c | d)) | (a & c & d);
+        for row in [0xfe10, 0xfe18, 0xfe20].iter() {
+            assert_eq!(word(&ram, *row), mixed);
+            let rest: Vec<u8> = (2..8).map(|i| ram.peek(row + i)).collect();
+            assert_eq!(rest, row3);
+        }
+        assert_eq!(ram.peek(0xfe28), 0x28u8.wrapping_mul(37) ^ 0x5a);
+
+        // on the first rows only the read corruption happens
+        let mut ram = Ram::origin(cartridge::Cartridge::default());
+        for a in 0xfe00..0xfea0 {
+            ram.write(a, a as u8);
+        }
+        ram.write(0xff40, 0x80);
+        let mut clock = 0;
+        while ram.video.get_video_mode() != 2 {
+            clock += 1;
+            io::Video::step(&mut ram, clock);
+        }
+        for _ in 0..2 {
+            clock += 1;
+            io::Video::step(&mut ram, clock);
+        }
+        let (a, b, c) = (word(&ram, 0xfe10), word(&ram, 0xfe08), word(&ram, 0xfe0c));
+        ram.read_idu(0xfe10);
+        assert_eq!(word(&ram, 0xfe10), b | (a & c));
+        assert_eq!(ram.peek(0xfe16), 0x0e);
+        assert_eq!(ram.peek(0xfe00), 0x00);
     }
-    pub fn pop16(&mut self, sp: &mut u16) -> u16 {
-        let (l, h) = self.pop88(sp);
-        u8tou16(l, h)
+
+    #[test]
+    fn cgb_system() {
+        let mut ram = Ram::origin(cartridge::Cartridge::default());
+        // DMG mode: one bank, no speed switch
+        ram.write(0xff70, 2);
+        assert_eq!(ram.read(0xff70), 0xff);
+        ram.write(0xff4d, 1);
+        assert!(!ram.switch_speed());
+
+        ram.set_cgb(true);
+        ram.write(0xc000, 0x10);
+        for bank in 0..8 {
+            ram.write(0xff70, bank);
+            ram.write(0xd000, bank + 0x20);
+        }
+        ram.write(0xff70, 0);
+        assert_eq!(ram.read(0xff70), 0xf9, "bank 0 is bank 1");
+        assert_eq!(ram.read(0xd000), 0x21);
+        ram.write(0xff70, 7);
+        assert_eq!(ram.read(0xd000), 0x27);
+        assert_eq!(ram.read(0xf000), 0x27, "echo");
+        assert_eq!(ram.read(0xc000), 0x10);
+
+        ram.write(0xff4d, 1);
+        assert_eq!(ram.read(0xff4d), 0x7f);
+        assert!(ram.switch_speed());
+        assert_eq!(ram.read(0xff4d), 0xfe);
+        assert!(!ram.switch_speed(), "KEY1 was cleared");
+
+        // general purpose: 2 blocks from wram to vram bank 1 at once
+        ram.write(0xff40, 0x00);
+        ram.write(0xff4f, 1);
+        for i in 0..0x20 {
+            ram.write(0xc100 + i, i as u8);
+        }
+        ram.write(0xff51, 0xc1);
+        ram.write(0xff52, 0x0f);
+        ram.write(0xff53, 0xe8);
+        ram.write(0xff54, 0x10);
+        ram.write(0xff55, 0x01);
+        assert_eq!(ram.read(0xff55), 0xff, "done");
+        assert_eq!(ram.stall, 2 * 16, "double speed");
+        assert_eq!(ram.read(0x8810), 0x00);
+        assert_eq!(ram.read(0x882f), 0x1f);
+        ram.write(0xff4f, 0);
+        assert_eq!(ram.read(0x8810), 0x00);
+        assert_eq!(ram.read(0x882f), 0x00, "bank 0 untouched");
+
+        // HBlank: a block per line, stopped after 2
+        ram.stall = 0;
+        ram.write(0xff51, 0xc1);
+        ram.write(0xff52, 0x00);
+        ram.write(0xff53, 0x00);
+        ram.write(0xff54, 0x00);
+        ram.write(0xff55, 0x83);
+        assert_eq!(ram.read(0xff55), 0x03);
+        ram.write(0xff40, 0x91);
+        for clock in 0..114 * 2 + 1 {
+            io::Video::step(&mut ram, clock);
+            io::Hdma::step(&mut ram, clock);
+        }
+        ram.write(0xff55, 0x00);
+        assert_eq!(ram.read(0xff55), 0x80 | 0x01, "stopped with 2 blocks left");
+        assert_eq!(ram.stall, 2 * 16);
+        ram.write(0xff40, 0x00);
+        assert_eq!(ram.read(0x801f), 0x1f);
+        assert_eq!(ram.read(0x8020), 0x00);
     }
 }
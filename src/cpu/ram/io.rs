@@ -1,6 +1,8 @@
 pub mod audio;
+pub mod sgb;
 pub mod video;
 pub use self::audio::Audio;
+pub use self::sgb::Sgb;
 pub use self::video::Video;
 
 use cpu::ram::Ram;
@@ -79,10 +81,6 @@ pub enum Interrupt {
 }
 
 pub struct InterruptManager {
-    pub master_enable: bool,
-    pub order_enable: bool,
-    pub order_disable: bool,
-
     enable_vblank: bool,
     enable_lcd_stat: bool,
     enable_timer: bool,
@@ -99,10 +97,6 @@ pub struct InterruptManager {
 impl InterruptManager {
     pub fn origin() -> InterruptManager {
         InterruptManager {
-            master_enable: false,
-            order_disable: false,
-            order_enable: false,
-
             enable_vblank: false,
             enable_lcd_stat: false,
             enable_timer: false,
@@ -117,18 +111,6 @@ impl InterruptManager {
         }
     }
 
-    pub fn step(ram: &mut ram::Ram, _clock: u32) -> Interrupt {
-        if ram.interrupt.order_disable {
-            ram.interrupt.order_disable = false;
-            ram.interrupt.master_enable = false;
-        }
-        if ram.interrupt.order_enable {
-            ram.interrupt.order_enable = false;
-            ram.interrupt.master_enable = true;
-        }
-        Interrupt::None
-    }
-
     pub fn add_interrupt(&mut self, i: &Interrupt) -> bool {
         match i {
             Interrupt::VBlank => self.request_vblank = true,
@@ -142,34 +124,34 @@ impl InterruptManager {
     }
 
     pub fn try_interrupt(ram: &mut ram::Ram, reg: &mut registers::Registers) {
-        if ram.interrupt.master_enable {
+        if reg.ime {
             if ram.interrupt.enable_vblank && ram.interrupt.request_vblank {
                 //println!("running Vblank");
-                ram.interrupt.master_enable = false;
+                reg.ime = false;
                 ram.interrupt.request_vblank = false;
                 ram.push16(&mut reg.sp, reg.pc);
                 reg.pc = 0x40;
             } else if ram.interrupt.enable_lcd_stat && ram.interrupt.request_lcd_stat {
                 println!("running lcd_stat");
-                ram.interrupt.master_enable = false;
+                reg.ime = false;
                 ram.interrupt.request_lcd_stat = false;
                 ram.push16(&mut reg.sp, reg.pc);
                 reg.pc = 0x48;
             } else if ram.interrupt.enable_timer && ram.interrupt.request_timer {
                 //println!("running timer" );
-                ram.interrupt.master_enable = false;
+                reg.ime = false;
                 ram.interrupt.request_timer = false;
                 ram.push16(&mut reg.sp, reg.pc);
                 reg.pc = 0x50;
             } else if ram.interrupt.enable_serial && ram.interrupt.request_serial {
                 //println!("running serial" );
-                ram.interrupt.master_enable = false;
+                reg.ime = false;
                 ram.interrupt.request_serial = false;
                 ram.push16(&mut reg.sp, reg.pc);
                 reg.pc = 0x58;
             } else if ram.interrupt.enable_joypad && ram.interrupt.request_joypad {
                 //println!("running joypad" );
-                ram.interrupt.master_enable = false;
+                reg.ime = false;
                 ram.interrupt.request_joypad = false;
                 ram.push16(&mut reg.sp, reg.pc);
                 reg.pc = 0x60;
@@ -311,27 +293,46 @@ impl Joypad {
             EmuKeys::Right => self.right = false,
         };
     }
+    // the direction and button held, like "left+a", the CGB boot rom reads
+    // them to pick a compatibility palette
+    pub fn combo(&self) -> Option<String> {
+        let direction = if self.up {
+            "up"
+        } else if self.down {
+            "down"
+        } else if self.left {
+            "left"
+        } else if self.right {
+            "right"
+        } else {
+            return None;
+        };
+        let button = if self.a {
+            "+a"
+        } else if self.b {
+            "+b"
+        } else {
+            ""
+        };
+        Some(format!("{}{}", direction, button))
+    }
     pub fn write(&mut self, v: u8) {
         self.p14 = (v & (1 << 4)) != 0;
         self.p15 = (v & (1 << 5)) != 0;
         //        println!("selection input p14{} p15{}",self.p14,self.p15);
     }
     pub fn read(&self) -> u8 {
-        // unsure, assuming out port is out only.
-        let mut r = 0;
+        // the selected lines pull the pressed keys low, the others read 1
+        let mut r = 0x0f;
         r |= (self.p14 as u8) << 4;
         r |= (self.p15 as u8) << 5;
         if !self.p14 {
-            r |= (!self.right as u8) << 0;
-            r |= (!self.left as u8) << 1;
-            r |= (!self.up as u8) << 2;
-            r |= (!self.down as u8) << 3;
+            r &= !((self.right as u8) | (self.left as u8) << 1);
+            r &= !((self.up as u8) << 2 | (self.down as u8) << 3);
         }
         if !self.p15 {
-            r |= (!self.a as u8) << 0;
-            r |= (!self.b as u8) << 1;
-            r |= (!self.select as u8) << 2;
-            r |= (!self.start as u8) << 3;
+            r &= !((self.a as u8) | (self.b as u8) << 1);
+            r &= !((self.select as u8) << 2 | (self.start as u8) << 3);
         }
         //        println!("reading buttons {:02x}",r);
         r
@@ -352,6 +353,8 @@ pub struct Serial {
     internal_clock: bool,
     stoptime: u32,
     data: u8,
+    // every byte sent, only recorded when set (used by the test roms harness)
+    pub capture: Option<Vec<u8>>,
 }
 impl Serial {
     pub fn origin() -> Serial {
@@ -361,6 +364,7 @@ impl Serial {
             internal_clock: false,
             data: 0,
             stoptime: 0,
+            capture: None,
         }
     }
     pub fn write_data(&mut self, v: u8) {
@@ -374,6 +378,11 @@ impl Serial {
         //    println!("Serial Control {}",v);
         self.start = (v & (1 << 7)) != 0;
         self.internal_clock = (v & 1) != 0;
+        if self.start && self.internal_clock {
+            if let Some(ref mut c) = self.capture {
+                c.push(self.data);
+            }
+        }
     }
     pub fn read_control(&self) -> u8 {
         let mut r = 0;
@@ -401,47 +410,167 @@ impl Serial {
     }
 }
 
+// OAM DMA: one byte per machine cycle for 160 cycles, after a cycle of setup.
+// While it runs the cpu only reaches the io registers and high ram, other
+// reads see the byte being copied.
 pub struct Dma {
     pub address: u8,
-    pub started: bool,
+    // cycles before the last written transfer starts
+    delay: u8,
+    active: bool,
+    source: u16,
     index: u8,
+    // last byte copied, what the cpu reads from the blocked bus
+    pub byte: u8,
 }
 impl Dma {
     pub fn origin() -> Dma {
         Dma {
             address: 0,
-            started: false,
+            delay: 0,
+            active: false,
+            source: 0,
             index: 0,
+            byte: 0xff,
         }
     }
     pub fn write(&mut self, v: u8) {
-        println!("##### WRITE DMA");
         self.address = v;
-        self.started = true;
-        self.index = 0;
+        // a running transfer goes on until the new one starts
+        self.delay = 2;
     }
     pub fn read(&self) -> u8 {
         self.address
     }
+    pub fn blocks(&self, a: u16) -> bool {
+        self.active && a < 0xff00
+    }
     pub fn step(ram: &mut Ram, _clock: u32) -> Interrupt {
-        if ram.dma.started {
-            let tmp = ram.read8(ram.dma.index, ram.dma.address);
-            ram.write8(ram.dma.index, 0xfe, tmp);
+        if ram.dma.active {
+            let v = ram.peek(ram.dma.source + ram.dma.index as u16);
+            ram.dma.byte = v;
+            ram.video.write_oam(ram.dma.index as u16, v);
             ram.dma.index += 1;
-            if ram.dma.index > 160 {
-                ram.dma.started = false;
+            if ram.dma.index == 160 {
+                ram.dma.active = false;
+            }
+        }
+        if ram.dma.delay > 0 {
+            ram.dma.delay -= 1;
+            if ram.dma.delay == 0 {
+                // 0xe000 and above read the internal ram echo
+                let page = if ram.dma.address >= 0xe0 {
+                    ram.dma.address - 0x20
+                } else {
+                    ram.dma.address
+                };
+                ram.dma.source = (page as u16) << 8;
+                ram.dma.index = 0;
+                ram.dma.active = true;
             }
         }
         Interrupt::None
     }
 }
 
+// CGB vram DMA (HDMA1-5): copies blocks of 16 bytes to vram, all of them at
+// once (general purpose) or one at the start of each HBlank. The cpu is
+// stopped while a block is copied.
+pub struct Hdma {
+    source: u16,
+    dest: u16,
+    // blocks left minus one, 0xff once done
+    length: u8,
+    // an HBlank transfer is running
+    hblank: bool,
+    // the ppu was drawing on the last cycle
+    drawing: bool,
+}
+impl Hdma {
+    pub fn origin() -> Hdma {
+        Hdma {
+            source: 0,
+            dest: 0,
+            length: 0xff,
+            hblank: false,
+            drawing: false,
+        }
+    }
+    pub fn write_source_high(&mut self, v: u8) {
+        self.source = (self.source & 0x00ff) | (v as u16) << 8;
+    }
+    pub fn write_source_low(&mut self, v: u8) {
+        self.source = (self.source & 0xff00) | (v & 0xf0) as u16;
+    }
+    // the destination is always in vram
+    pub fn write_dest_high(&mut self, v: u8) {
+        self.dest = (self.dest & 0x00ff) | ((v & 0x1f) as u16) << 8;
+    }
+    pub fn write_dest_low(&mut self, v: u8) {
+        self.dest = (self.dest & 0xff00) | (v & 0xf0) as u16;
+    }
+    // bit 7 is clear while an HBlank transfer runs
+    pub fn read_control(&self) -> u8 {
+        if self.hblank {
+            self.length & 0x7f
+        } else {
+            0x80 | self.length
+        }
+    }
+    pub fn write_control(ram: &mut Ram, v: u8) {
+        if ram.hdma.hblank && v & 0x80 == 0 {
+            // stops the HBlank transfer, the length is kept
+            ram.hdma.hblank = false;
+            return;
+        }
+        ram.hdma.length = v & 0x7f;
+        if v & 0x80 != 0 {
+            ram.hdma.hblank = true;
+        } else {
+            while ram.hdma.length != 0xff {
+                Hdma::copy_block(ram);
+            }
+        }
+    }
+    fn copy_block(ram: &mut Ram) {
+        for i in 0..16 {
+            let v = ram.peek(ram.hdma.source.wrapping_add(i));
+            ram.video.write_vram((ram.hdma.dest + i) & 0x1fff, v);
+        }
+        ram.hdma.source = ram.hdma.source.wrapping_add(16);
+        ram.hdma.dest = (ram.hdma.dest + 16) & 0x1fff;
+        ram.hdma.length = ram.hdma.length.wrapping_sub(1);
+        // 8 cycles a block at normal speed, the copy takes as long in double speed
+        ram.stall += if ram.double_speed { 16 } else { 8 };
+    }
+    pub fn step(ram: &mut Ram, _clock: u32) -> Interrupt {
+        // HBlank starts when mode 3 ends, not when the lcd is turned off
+        let enabled = ram.video.lcd_enabled();
+        let drawing = enabled && ram.video.get_video_mode() == 3;
+        if ram.hdma.drawing && !drawing && enabled && ram.hdma.hblank {
+            Hdma::copy_block(ram);
+            if ram.hdma.length == 0xff {
+                ram.hdma.hblank = false;
+            }
+        }
+        ram.hdma.drawing = drawing;
+        Interrupt::None
+    }
+}
+
+// DIV is the upper byte of a 16 bit counter running at 4MHz, TIMA counts the
+// falling edges of one of its bits (selected by TAC) anded with the enable bit.
+// On overflow TIMA reads 0 for a cycle, then gets TMA and raises the interrupt.
 pub struct Timer {
-    div: u8,
+    counter: u16,
     tima: u8,
     tma: u8,
     start: bool,
     div_sel: u8,
+    // TIMA overflowed on the last cycle and is reloaded on this one
+    overflow: bool,
+    // TIMA was reloaded on the last cycle, cpu writes to it are lost
+    reloading: bool,
 }
 impl Timer {
     pub fn origin() -> Timer {
@@ -450,35 +579,68 @@ impl Timer {
         // 10: 65.536 KHz   (~67.11 KHz SGB)  /16
         // 11: 16.384 KHz   (~16.78 KHz SGB)  /64
         Timer {
-            div: 0,
+            counter: 0,
             tima: 0,
             tma: 0,
             div_sel: 0,
             start: false,
+            overflow: false,
+            reloading: false,
         }
     }
+    fn input(&self) -> bool {
+        let bit = match self.div_sel {
+            0 => 9,
+            1 => 3,
+            2 => 5,
+            _ => 7,
+        };
+        self.start && self.counter & (1 << bit) != 0
+    }
+    fn increment(&mut self) {
+        let (r, o) = self.tima.overflowing_add(1);
+        self.tima = r;
+        self.overflow = o;
+    }
     pub fn write_div(&mut self, _v: u8) {
-        self.div = 0;
-        println!("TIMER write DIV {}", _v);
+        let before = self.input();
+        self.counter = 0;
+        if before {
+            self.increment();
+        }
+        println!("TIMER write DIV {}", _v);
     }
     pub fn write_tima(&mut self, v: u8) {
-        self.tima = v;
+        if !self.reloading {
+            self.tima = v;
+            self.overflow = false;
+        }
         println!("TIMER write TIMA {}", v);
     }
     pub fn write_tma(&mut self, v: u8) {
         self.tma = v;
+        if self.reloading {
+            self.tima = v;
+        }
         println!("TIMER write TMA {}", v);
     }
     pub fn write_control(&mut self, v: u8) {
+        let before = self.input();
         self.div_sel = v & 0x3;
         self.start = v & 0x4 != 0;
+        if before && !self.input() {
+            self.increment();
+        }
         println!(
             "TIMER write control {:02x} {} {}",
             v, self.div_sel, self.start
         );
     }
+    pub fn set_counter(&mut self, v: u16) {
+        self.counter = v;
+    }
     pub fn read_div(&self) -> u8 {
-        self.div
+        (self.counter >> 8) as u8
     }
     pub fn read_tima(&self) -> u8 {
         self.tima
@@ -489,32 +651,207 @@ impl Timer {
     pub fn read_control(&self) -> u8 {
         self.div_sel | ((self.start as u8) << 2)
     }
-    pub fn step(ram: &mut Ram, clock: u32) -> Interrupt {
-        if clock & 63 == 0 {
-            ram.timer.div = ram.timer.div.wrapping_add(1);
+    pub fn step(ram: &mut Ram, _clock: u32) -> Interrupt {
+        let timer = &mut ram.timer;
+        timer.reloading = false;
+        let mut interrupt = Interrupt::None;
+        if timer.overflow {
+            timer.overflow = false;
+            timer.tima = timer.tma;
+            timer.reloading = true;
+            interrupt = Interrupt::TimerOverflow;
         }
-        if ram.timer.start
-            && (clock
-                & match ram.timer.div_sel {
-                    0 => 255,
-                    1 => 3,
-                    2 => 15,
-                    3 => 63,
-                    _ => panic!(),
-                })
-                == 2
-        {
-            let (r, o) = ram.timer.tima.overflowing_add(1);
-            //            println!("clock tick {} {}",r,o);
-            if o {
-                ram.timer.tima = ram.timer.tma;
-                return Interrupt::TimerOverflow;
-            } else {
-                ram.timer.tima = r;
-                return Interrupt::None;
-            }
-        } else {
-            return Interrupt::None;
+        let before = timer.input();
+        timer.counter = timer.counter.wrapping_add(4);
+        if before && !timer.input() {
+            timer.increment();
+        }
+        interrupt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::cartridge::Cartridge;
+
+    fn ram() -> Ram {
+        Ram::origin(Cartridge::default())
+    }
+
+    fn dma_steps(ram: &mut Ram, n: usize) {
+        for _ in 0..n {
+            Dma::step(ram, 0);
+        }
+    }
+
+    // work ram filled with `page + index`, oam with 0
+    fn dma_ram() -> Ram {
+        let mut ram = ram();
+        for a in 0xc000..0xe000u16 {
+            ram.write(a, (a >> 8) as u8 ^ a as u8);
+        }
+        ram
+    }
+
+    #[test]
+    fn dma_timing() {
+        let mut ram = dma_ram();
+        ram.write(0xff46, 0xc1);
+        dma_steps(&mut ram, 1);
+        assert_eq!(ram.read(0xc000), 0xc0, "the bus is free before the start");
+        dma_steps(&mut ram, 1);
+        assert_eq!(ram.peek(0xfe00), 0x00, "nothing copied during the delay");
+        dma_steps(&mut ram, 159);
+        assert_eq!(ram.peek(0xfe00 + 158), 0xc1 ^ 158);
+        assert_eq!(ram.peek(0xfe00 + 159), 0x00);
+        assert_eq!(ram.read(0xc000), 0xc1 ^ 158, "still running");
+        dma_steps(&mut ram, 1);
+        assert_eq!(ram.peek(0xfe00 + 159), 0xc1 ^ 159);
+        assert_eq!(ram.read(0xc000), 0xc0, "done after 160 cycles");
+        assert_eq!(ram.read(0xff46), 0xc1);
+    }
+
+    #[test]
+    fn dma_bus_conflict() {
+        let mut ram = dma_ram();
+        ram.write(0xff80, 0x42);
+        ram.write(0xff46, 0xc0);
+        dma_steps(&mut ram, 2 + 10);
+        // the cpu sees the byte being copied except in hram and io
+        assert_eq!(ram.read(0x0150), 0xc0 ^ 9);
+        assert_eq!(ram.read(0xd123), 0xc0 ^ 9);
+        assert_eq!(ram.read(0xfe00), 0xff, "oam is not readable");
+        assert_eq!(ram.read(0xff80), 0x42);
+        assert_eq!(ram.read(0xff46), 0xc0);
+        ram.write(0xc000, 0x99);
+        assert_eq!(ram.peek(0xc000), 0xc0, "writes are lost");
+        ram.write(0xff80, 0x43);
+        assert_eq!(ram.read(0xff80), 0x43);
+    }
+
+    #[test]
+    fn dma_restart() {
+        let mut ram = dma_ram();
+        ram.write(0xff46, 0xc0);
+        dma_steps(&mut ram, 2 + 50);
+        ram.write(0xff46, 0xd0);
+        // the first transfer goes on during the delay of the new one
+        dma_steps(&mut ram, 2);
+        assert_eq!(ram.peek(0xfe00 + 51), 0xc0 ^ 51);
+        assert_eq!(ram.peek(0xfe00 + 52), 0x00);
+        dma_steps(&mut ram, 1);
+        assert_eq!(ram.peek(0xfe00), 0xd0, "started again from the first byte");
+        assert_eq!(ram.peek(0xfe00 + 1), 0xc0 ^ 1);
+        dma_steps(&mut ram, 159);
+        assert_eq!(ram.peek(0xfe00 + 159), 0xd0 ^ 159);
+        assert_eq!(ram.read(0xc000), 0xc0, "one transfer of 160 cycles");
+    }
+
+    #[test]
+    fn dma_echo_source() {
+        for (page, source) in [(0xe1u8, 0xc1u8), (0xfe, 0xde), (0xff, 0xdf)].iter() {
+            let mut ram = dma_ram();
+            ram.write(0xff46, *page);
+            dma_steps(&mut ram, 2 + 160);
+            assert_eq!(ram.peek(0xfe00 + 7), source ^ 7, "{:02x}", page);
         }
     }
+
+    // `n` timer cycles, returns whether the last one raised the interrupt
+    fn timer_steps(ram: &mut Ram, n: usize) -> bool {
+        let mut irq = false;
+        for _ in 0..n {
+            irq = matches!(Timer::step(ram, 0), Interrupt::TimerOverflow);
+        }
+        irq
+    }
+
+    #[test]
+    fn timer_div_write() {
+        let mut ram = ram();
+        // TIMA counts on the falling edge of bit 3 of the counter
+        ram.write(0xff07, 0x05);
+        ram.write(0xff05, 0x10);
+        timer_steps(&mut ram, 1);
+        ram.write(0xff04, 0);
+        assert_eq!(ram.read(0xff05), 0x10, "the bit was low");
+        timer_steps(&mut ram, 2);
+        ram.write(0xff04, 0);
+        assert_eq!(ram.read(0xff05), 0x11, "resetting DIV makes the bit fall");
+        timer_steps(&mut ram, 3);
+        assert_eq!(ram.read(0xff05), 0x11, "counting from 0 again");
+        timer_steps(&mut ram, 1);
+        assert_eq!(ram.read(0xff05), 0x12);
+    }
+
+    #[test]
+    fn timer_tac_change() {
+        let mut ram = ram();
+        ram.write(0xff07, 0x05);
+        timer_steps(&mut ram, 2);
+        // bit 3 is high, bit 9 is low
+        ram.write(0xff07, 0x04);
+        assert_eq!(ram.read(0xff05), 0x01, "selecting a low bit");
+        ram.write(0xff07, 0x05);
+        ram.write(0xff07, 0x01);
+        assert_eq!(ram.read(0xff05), 0x02, "stopping the timer");
+        ram.write(0xff07, 0x05);
+        assert_eq!(ram.read(0xff05), 0x02, "a rising edge does nothing");
+        timer_steps(&mut ram, 2);
+        assert_eq!(ram.read(0xff05), 0x03);
+        ram.write(0xff07, 0x06);
+        assert_eq!(ram.read(0xff05), 0x03, "both bits are low");
+    }
+
+    // TIMA overflowing at the 4th cycle from a counter at 0
+    fn timer_overflow(ram: &mut Ram) {
+        ram.write(0xff06, 0xab);
+        ram.write(0xff05, 0xff);
+        ram.write(0xff07, 0x05);
+        assert!(!timer_steps(ram, 4));
+        assert_eq!(ram.read(0xff05), 0x00, "TIMA is 0 for one cycle");
+    }
+
+    #[test]
+    fn timer_reload() {
+        let mut ram = ram();
+        timer_overflow(&mut ram);
+        assert!(timer_steps(&mut ram, 1), "the interrupt comes with the reload");
+        assert_eq!(ram.read(0xff05), 0xab);
+        assert!(!timer_steps(&mut ram, 1));
+    }
+
+    #[test]
+    fn timer_write_tima_before_reload() {
+        let mut ram = ram();
+        timer_overflow(&mut ram);
+        ram.write(0xff05, 0x33);
+        assert!(!timer_steps(&mut ram, 1), "the reload and the interrupt are cancelled");
+        assert_eq!(ram.read(0xff05), 0x33);
+    }
+
+    #[test]
+    fn timer_write_tima_while_reloading() {
+        let mut ram = ram();
+        timer_overflow(&mut ram);
+        timer_steps(&mut ram, 1);
+        ram.write(0xff05, 0x33);
+        assert_eq!(ram.read(0xff05), 0xab, "the write is lost");
+        timer_steps(&mut ram, 1);
+        ram.write(0xff05, 0x33);
+        assert_eq!(ram.read(0xff05), 0x33);
+    }
+
+    #[test]
+    fn timer_write_tma_while_reloading() {
+        let mut ram = ram();
+        timer_overflow(&mut ram);
+        timer_steps(&mut ram, 1);
+        ram.write(0xff06, 0x77);
+        assert_eq!(ram.read(0xff05), 0x77, "TIMA takes the new TMA");
+        timer_steps(&mut ram, 1);
+        ram.write(0xff06, 0x88);
+        assert_eq!(ram.read(0xff05), 0x77);
+    }
 }
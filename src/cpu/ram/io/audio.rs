@@ -72,7 +72,6 @@ impl Noise {
         self.write_envelope(0);
         self.write_frequency_hi(0);
         self.write_shift_reg(0);
-        self.write_lp(0);
         //self.period = 0;
         self.enable = false;
     }
@@ -341,7 +340,6 @@ impl Wave {
     pub fn clear(&mut self) {
         self.write_frequency_hi(0);
         self.write_frequency_lo(0);
-        self.write_lp(0);
         self.write_power(0);
         self.write_volume(0);
         self.cursor = 0;
@@ -598,7 +596,7 @@ impl Square {
         self.write_envelope(0);
         self.write_frequency_hi(0);
         self.write_frequency_lo(0);
-        self.write_lp(0);
+        self.duty = 0;
         self.write_sweep(0);
         self.frequency = 0;
         self.next_change = 0;
@@ -776,6 +774,9 @@ impl Square {
     }
     pub fn write_lp(&mut self, v: u8) {
         self.duty = (v >> 6) & 0x3;
+        self.write_length(v);
+    }
+    pub fn write_length(&mut self, v: u8) {
         self.set_length = v & 0x3f;
         if self.set_length == 0 {
             self.length = 63;
@@ -901,6 +902,9 @@ pub struct Audio {
     pub override_sound2: Option<bool>,
     pub override_sound3: Option<bool>,
     pub override_sound4: Option<bool>,
+    // the CGB apu: lengths cleared by powering off, wave ram reachable while
+    // channel 3 plays
+    pub cgb_hardware: bool,
 }
 
 impl Audio {
@@ -934,6 +938,7 @@ impl Audio {
             override_sound2: None,
             override_sound3: None,
             override_sound4: None,
+            cgb_hardware: false,
         }
     }
 
@@ -941,7 +946,16 @@ impl Audio {
         print!("w {:02x}={:02x}", a, v);
         match a {
             0x26 => self.write_power_flag(v),
-            0x30..=0x3f => self.wave3.write_sample_ram(a - 0x30, v),
+            0x30..=0x3f => {
+                if let Some(a) = self.wave_ram_index(a - 0x30) {
+                    self.wave3.write_sample_ram(a, v);
+                }
+            }
+            // the DMG can still write the length counters while powered off
+            0x11 if !self.power && !self.cgb_hardware => self.square1.write_length(v),
+            0x16 if !self.power && !self.cgb_hardware => self.square2.write_length(v),
+            0x1b if !self.power && !self.cgb_hardware => self.wave3.write_lp(v),
+            0x20 if !self.power && !self.cgb_hardware => self.noise4.write_lp(v),
             _ => {
                 if self.power {
                     match a {
@@ -987,12 +1001,32 @@ impl Audio {
             0x24 => self.read_stereo_volume(),
             0x25 => self.read_output_selection(),
             0x26 => self.read_power_flag(),
-            0x30..=0x3f => self.wave3.read_sample_ram(a - 0x30),
+            0x30..=0x3f => match self.wave_ram_index(a - 0x30) {
+                Some(a) => self.wave3.read_sample_ram(a),
+                None => 0xff,
+            },
             _ => 0xff,
         };
         print!("r >{:02x}\n", r);
         r
     }
+    // channel 1 stays on after the logo sound of the boot rom, faded out
+    pub fn end_boot_sound(&mut self) {
+        self.square1.enable = true;
+        self.square1.volume = 0;
+    }
+    // while channel 3 plays the wave ram only reaches the byte it is reading:
+    // on the CGB every access goes there, on the DMG only the ones on the
+    // cycle it reads it, which is not emulated (reads give 0xff, writes are lost)
+    fn wave_ram_index(&self, a: u16) -> Option<u16> {
+        if !self.wave3.enable {
+            Some(a)
+        } else if self.cgb_hardware {
+            Some(self.wave3.cursor as u16 / 2)
+        } else {
+            None
+        }
+    }
     pub fn write_stereo_volume(&mut self, v: u8) {
         self.left_enable = bit(v, 7);
         self.right_enable = bit(v, 3);
@@ -1066,6 +1100,13 @@ impl Audio {
             self.square2.clear();
             self.wave3.clear();
             self.noise4.clear();
+            // the DMG keeps the length counters
+            if self.cgb_hardware {
+                self.square1.write_length(0);
+                self.square2.write_length(0);
+                self.wave3.write_lp(0);
+                self.noise4.write_lp(0);
+            }
             self.write_stereo_volume(0x00);
             self.write_output_selection(0x00);
             if AUDIO_DEBUG {
@@ -1251,6 +1292,39 @@ mod tests {
         }
     }
     #[test]
+    fn model_differences() {
+        for cgb in [false, true].iter() {
+            let mut a = audio::Audio::origin();
+            a.cgb_hardware = *cgb;
+            // a length of 2 written while off only sticks on the DMG
+            a.write_register(0x26, 0x00);
+            a.write_register(0x11, 0x3e);
+            a.write_register(0x26, 0x80);
+            a.write_register(0x12, 0xf0);
+            a.write_register(0x14, 0xc0);
+            a.step(0);
+            a.square1.lenght_decr();
+            a.square1.lenght_decr();
+            assert_eq!(a.read_register(0x26) & 1, *cgb as u8, "cgb {}", cgb);
+
+            // the wave ram while channel 3 plays
+            a.write_register(0x1a, 0x80);
+            a.write_register(0x1e, 0x80);
+            a.step(1);
+            a.write_register(0x3f, 0x5a);
+            if *cgb {
+                assert_eq!(a.read_register(0x30), 0x5a);
+                assert_eq!(a.read_register(0x35), 0x5a);
+            } else {
+                assert_eq!(a.read_register(0x30), 0xff);
+            }
+            a.write_register(0x26, 0x00);
+            // the CGB wrote the byte played, the DMG lost it
+            assert_eq!(a.read_register(0x30), if *cgb { 0x5a } else { 0x84 });
+            assert_eq!(a.read_register(0x3f), 0xda);
+        }
+    }
+    #[test]
     fn lfsr() {
         let mut n = audio::Noise::origin();
         n.width_mode = true;
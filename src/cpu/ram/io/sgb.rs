@@ -0,0 +1,641 @@
+// Super Game Boy: the game sends commands to the SNES in packets of 16 bytes,
+// one bit per pulse on the joypad lines (P14 low for 0, P15 low for 1, both
+// low to start a packet). The SNES gives colors to the 4 shades of the screen
+// with 4 palettes chosen for each 8x8 cell, and draws the 256x224 picture with
+// a border around it. Bigger data (palettes, attributes, border) is sent by
+// showing it on the screen as tiles after a *_TRN command.
+
+use super::video::cgb::rgb555_to_rgb;
+use super::video::Video;
+
+const SGB_DEBUG: bool = false;
+
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 224;
+// position of the game screen in the picture
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+
+// 8x8 cells of the game screen
+const CELLS_X: usize = 20;
+const CELLS_Y: usize = 18;
+
+// the SGB default palette 1-A
+const DEFAULT_PALETTE: [u16; 4] = [0x67bf, 0x265b, 0x10b5, 0x2866];
+
+// what the screen shows with MASK_EN
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mask {
+    None,
+    // keeps the last picture
+    Freeze,
+    Black,
+    // color 0 of palette 0
+    Color0,
+}
+
+// the data expected on the next frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Transfer {
+    Palettes,
+    // the second half of the border tiles
+    Tiles(bool),
+    Border,
+    Attributes,
+}
+
+pub struct Sgb {
+    // the cartridge supports the SGB and runs on one
+    pub enabled: bool,
+    // P15 and P14 on the last joypad write
+    lines: u8,
+    receiving: bool,
+    // bits of the packet received
+    bit: usize,
+    packet: [u8; 16],
+    // packets of the command
+    data: Vec<u8>,
+    // MLT_REQ
+    players: u8,
+    player: u8,
+
+    palettes: [[u16; 4]; 4],
+    system_palettes: Vec<[u16; 4]>,
+    // palette of each cell
+    attributes: [u8; CELLS_X * CELLS_Y],
+    attribute_files: Vec<u8>,
+    mask: Mask,
+    transfer: Option<Transfer>,
+    // colors of the game screen
+    screen: Vec<u16>,
+
+    // 256 tiles of 4 bits per pixel in the SNES format
+    border_tiles: Vec<u8>,
+    // 32x28 entries of tile, palette and flips
+    border_map: Vec<u16>,
+    // SNES palettes 4 to 7 used by the border
+    border_palettes: [[u16; 16]; 4],
+}
+
+impl Sgb {
+    pub fn origin() -> Sgb {
+        Sgb {
+            enabled: false,
+            lines: 3,
+            receiving: false,
+            bit: 0,
+            packet: [0; 16],
+            data: Vec::with_capacity(16 * 7),
+            players: 1,
+            player: 0,
+            palettes: [DEFAULT_PALETTE; 4],
+            system_palettes: vec![[0; 4]; 512],
+            attributes: [0; CELLS_X * CELLS_Y],
+            attribute_files: vec![0; 45 * 90],
+            mask: Mask::None,
+            transfer: None,
+            screen: vec![DEFAULT_PALETTE[0]; 160 * 144],
+            border_tiles: vec![0; 256 * 32],
+            border_map: vec![0; 32 * 32],
+            border_palettes: [[0; 16]; 4],
+        }
+    }
+
+    // the joypad register, written by the game
+    pub fn write_joypad(&mut self, v: u8) {
+        if !self.enabled {
+            return;
+        }
+        let lines = (v >> 4) & 3;
+        let last = std::mem::replace(&mut self.lines, lines);
+        if lines == last {
+            return;
+        }
+        match lines {
+            0 => {
+                self.receiving = true;
+                self.bit = 0;
+                self.packet = [0; 16];
+            }
+            // P14 low for 0, P15 low for 1
+            1 | 2 if self.receiving && last == 3 => {
+                if self.bit == 128 {
+                    // the stop bit
+                    self.receiving = false;
+                    self.end_packet();
+                } else {
+                    self.packet[self.bit / 8] |= (lines & 1) << (self.bit % 8);
+                    self.bit += 1;
+                }
+            }
+            // the next joypad is selected when P15 goes back high
+            3 if last == 1 && !self.receiving && self.players > 1 => {
+                self.player = (self.player + 1) % self.players;
+            }
+            _ => {}
+        }
+    }
+
+    // the joypad register as read by the game, `v` is the one of the first
+    // joypad, the others have no key pressed
+    pub fn read_joypad(&self, v: u8) -> u8 {
+        if !self.enabled || self.players == 1 {
+            v
+        } else if v & 0x30 == 0x30 {
+            (v & 0xf0) | (0x0f - self.player)
+        } else if self.player != 0 {
+            v | 0x0f
+        } else {
+            v
+        }
+    }
+
+    fn end_packet(&mut self) {
+        if self.data.is_empty() && self.packet[0] & 7 == 0 {
+            // not a command
+            return;
+        }
+        self.data.extend_from_slice(&self.packet);
+        let packets = (self.data[0] & 7) as usize;
+        if self.data.len() >= packets * 16 {
+            let data = std::mem::take(&mut self.data);
+            self.command(&data);
+        }
+    }
+
+    fn command(&mut self, data: &[u8]) {
+        let command = data[0] >> 3;
+        if SGB_DEBUG {
+            println!("sgb command {:02x}", command);
+        }
+        match command {
+            0x00 => self.set_palettes(0, 1, data),
+            0x01 => self.set_palettes(2, 3, data),
+            0x02 => self.set_palettes(0, 3, data),
+            0x03 => self.set_palettes(1, 2, data),
+            0x04 => self.attribute_blocks(data),
+            0x05 => self.attribute_lines(data),
+            0x06 => self.attribute_division(data),
+            0x07 => self.attribute_cells(data),
+            0x0a => {
+                for (i, p) in data[1..9].chunks(2).enumerate() {
+                    let index = (p[0] as usize | (p[1] as usize) << 8) & 0x1ff;
+                    self.palettes[i] = self.system_palettes[index];
+                }
+                // bit 7 also sets an attribute file
+                if data[9] & 0x80 != 0 {
+                    self.set_attribute_file(data[9]);
+                } else if data[9] & 0x40 != 0 {
+                    self.mask = Mask::None;
+                }
+            }
+            0x0b => self.transfer = Some(Transfer::Palettes),
+            0x11 => {
+                self.players = match data[1] & 3 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                };
+                self.player = 0;
+            }
+            0x13 => self.transfer = Some(Transfer::Tiles(data[1] & 1 != 0)),
+            0x14 => self.transfer = Some(Transfer::Border),
+            0x15 => self.transfer = Some(Transfer::Attributes),
+            0x16 => self.set_attribute_file(data[1]),
+            0x17 => {
+                self.mask = match data[1] & 3 {
+                    0 => Mask::None,
+                    1 => Mask::Freeze,
+                    2 => Mask::Black,
+                    _ => Mask::Color0,
+                }
+            }
+            _ => {
+                if SGB_DEBUG {
+                    println!("unimplemented sgb command {:02x}", command);
+                }
+            }
+        }
+    }
+
+    // PALxx: the shared color 0 then colors 1-3 of both palettes
+    fn set_palettes(&mut self, a: usize, b: usize, data: &[u8]) {
+        let color = |i: usize| data[1 + i * 2] as u16 | (data[2 + i * 2] as u16) << 8;
+        for p in self.palettes.iter_mut() {
+            p[0] = color(0);
+        }
+        for i in 1..4 {
+            self.palettes[a][i] = color(i);
+            self.palettes[b][i] = color(i + 3);
+        }
+    }
+
+    // bits 0-5 the file, bit 6 cancels the mask
+    fn set_attribute_file(&mut self, v: u8) {
+        let file = (v & 0x3f) as usize % 45;
+        for (i, a) in self.attributes.iter_mut().enumerate() {
+            let byte = self.attribute_files[file * 90 + i / 4];
+            *a = (byte >> (6 - i % 4 * 2)) & 3;
+        }
+        if v & 0x40 != 0 {
+            self.mask = Mask::None;
+        }
+    }
+
+    fn attribute_blocks(&mut self, data: &[u8]) {
+        let count = (data[1] as usize).min(18);
+        for set in data[2..].chunks(6).take(count) {
+            let control = set[0] & 7;
+            let inside = set[1] & 3;
+            let border = (set[1] >> 2) & 3;
+            let outside = (set[1] >> 4) & 3;
+            // a block with only its inside or outside colored gets its border too
+            let (control, border) = match control {
+                1 => (3, inside),
+                4 => (6, outside),
+                _ => (control, border),
+            };
+            let (x1, y1, x2, y2) = (
+                set[2] as usize,
+                set[3] as usize,
+                set[4] as usize,
+                set[5] as usize,
+            );
+            for y in 0..CELLS_Y {
+                for x in 0..CELLS_X {
+                    let within = x >= x1 && x <= x2 && y >= y1 && y <= y2;
+                    let on_border = within && (x == x1 || x == x2 || y == y1 || y == y2);
+                    let palette = if on_border {
+                        (control & 2 != 0).then_some(border)
+                    } else if within {
+                        (control & 1 != 0).then_some(inside)
+                    } else {
+                        (control & 4 != 0).then_some(outside)
+                    };
+                    if let Some(p) = palette {
+                        self.attributes[y * CELLS_X + x] = p;
+                    }
+                }
+            }
+        }
+    }
+
+    fn attribute_lines(&mut self, data: &[u8]) {
+        let count = data[1] as usize;
+        for v in data[2..].iter().take(count) {
+            let line = (v & 0x1f) as usize;
+            let palette = (v >> 5) & 3;
+            if v & 0x80 != 0 {
+                if line < CELLS_Y {
+                    for x in 0..CELLS_X {
+                        self.attributes[line * CELLS_X + x] = palette;
+                    }
+                }
+            } else if line < CELLS_X {
+                for y in 0..CELLS_Y {
+                    self.attributes[y * CELLS_X + line] = palette;
+                }
+            }
+        }
+    }
+
+    fn attribute_division(&mut self, data: &[u8]) {
+        let after = data[1] & 3;
+        let before = (data[1] >> 2) & 3;
+        let on = (data[1] >> 4) & 3;
+        let horizontal = data[1] & 0x40 != 0;
+        let line = data[2] as usize;
+        for y in 0..CELLS_Y {
+            for x in 0..CELLS_X {
+                let c = if horizontal { y } else { x };
+                self.attributes[y * CELLS_X + x] = match c {
+                    c if c < line => before,
+                    c if c == line => on,
+                    _ => after,
+                };
+            }
+        }
+    }
+
+    fn attribute_cells(&mut self, data: &[u8]) {
+        let mut x = data[1] as usize % CELLS_X;
+        let mut y = data[2] as usize % CELLS_Y;
+        let count = (data[3] as usize | (data[4] as usize) << 8).min(CELLS_X * CELLS_Y);
+        let vertical = data[5] & 1 != 0;
+        for i in 0..count {
+            let byte = match data.get(6 + i / 4) {
+                Some(b) => *b,
+                None => break,
+            };
+            self.attributes[y * CELLS_X + x] = (byte >> (6 - i % 4 * 2)) & 3;
+            if vertical {
+                y += 1;
+                if y == CELLS_Y {
+                    y = 0;
+                    x = (x + 1) % CELLS_X;
+                }
+            } else {
+                x += 1;
+                if x == CELLS_X {
+                    x = 0;
+                    y = (y + 1) % CELLS_Y;
+                }
+            }
+        }
+    }
+
+    // the 4KB shown on the screen as 256 tiles, 20 per line, read back from
+    // the shades of the frame
+    fn capture(video: &Video) -> Vec<u8> {
+        let mut out = vec![0; 0x1000];
+        for (tile, bytes) in out.chunks_mut(16).enumerate() {
+            let (tx, ty) = (tile % 20, tile / 20);
+            for row in 0..8 {
+                let mut lo = 0;
+                let mut hi = 0;
+                for column in 0..8 {
+                    let shade = video.back_buffer[(ty * 8 + row) * 160 + tx * 8 + column] & 3;
+                    lo |= (shade & 1) << (7 - column);
+                    hi |= (shade >> 1) << (7 - column);
+                }
+                bytes[row * 2] = lo;
+                bytes[row * 2 + 1] = hi;
+            }
+        }
+        out
+    }
+
+    // at the end of each frame: takes the data of a transfer and colors the screen
+    pub fn vblank(&mut self, video: &Video) {
+        if !self.enabled {
+            return;
+        }
+        if let Some(transfer) = self.transfer.take() {
+            let data = Sgb::capture(video);
+            let word = |i: usize| data[i] as u16 | (data[i + 1] as u16) << 8;
+            match transfer {
+                Transfer::Palettes => {
+                    for (i, p) in self.system_palettes.iter_mut().enumerate() {
+                        for (c, color) in p.iter_mut().enumerate() {
+                            *color = word(i * 8 + c * 2);
+                        }
+                    }
+                }
+                Transfer::Tiles(high) => {
+                    let start = if high { 0x1000 } else { 0 };
+                    self.border_tiles[start..start + 0x1000].copy_from_slice(&data);
+                }
+                Transfer::Border => {
+                    for (i, entry) in self.border_map.iter_mut().enumerate() {
+                        *entry = word(i * 2);
+                    }
+                    for (p, palette) in self.border_palettes.iter_mut().enumerate() {
+                        for (c, color) in palette.iter_mut().enumerate() {
+                            *color = word(0x800 + p * 32 + c * 2);
+                        }
+                    }
+                }
+                Transfer::Attributes => {
+                    let len = self.attribute_files.len();
+                    self.attribute_files.copy_from_slice(&data[..len]);
+                }
+            }
+        }
+        let color0 = self.palettes[0][0];
+        for (i, color) in self.screen.iter_mut().enumerate() {
+            *color = match self.mask {
+                Mask::Freeze => continue,
+                Mask::Black => 0,
+                Mask::Color0 => color0,
+                Mask::None => {
+                    let cell = (i / 160 / 8) * CELLS_X + i % 160 / 8;
+                    let shade = video.back_buffer[i] & 3;
+                    self.palettes[self.attributes[cell] as usize][shade as usize]
+                }
+            };
+        }
+    }
+
+    // the color index of a pixel of a border tile
+    fn border_pixel(&self, tile: usize, x: usize, y: usize) -> u8 {
+        let t = &self.border_tiles[tile * 32..tile * 32 + 32];
+        let bit = |b: u8| (b >> (7 - x)) & 1;
+        bit(t[y * 2]) | bit(t[y * 2 + 1]) << 1 | bit(t[16 + y * 2]) << 2 | bit(t[17 + y * 2]) << 3
+    }
+
+    // the 256x224 RGBA picture, the screen is blank while the lcd is off
+    pub fn frame(&self, lcd_off: bool) -> Vec<u8> {
+        let backdrop = self.palettes[0][0];
+        let mut colors = vec![backdrop; WIDTH * HEIGHT];
+        if !lcd_off {
+            for (y, line) in self.screen.chunks(160).enumerate() {
+                let start = (SCREEN_Y + y) * WIDTH + SCREEN_X;
+                colors[start..start + 160].copy_from_slice(line);
+            }
+        }
+        for ty in 0..HEIGHT / 8 {
+            for tx in 0..WIDTH / 8 {
+                let entry = self.border_map[ty * 32 + tx];
+                let tile = (entry & 0xff) as usize;
+                let palette = ((entry >> 10) & 3) as usize;
+                for y in 0..8 {
+                    for x in 0..8 {
+                        let px = if entry & 0x4000 != 0 { 7 - x } else { x };
+                        let py = if entry & 0x8000 != 0 { 7 - y } else { y };
+                        let c = self.border_pixel(tile, px, py);
+                        if c != 0 {
+                            colors[(ty * 8 + y) * WIDTH + tx * 8 + x] =
+                                self.border_palettes[palette][c as usize];
+                        }
+                    }
+                }
+            }
+        }
+        let mut out = Vec::with_capacity(WIDTH * HEIGHT * 4);
+        for c in colors {
+            out.extend_from_slice(&rgb555_to_rgb(c));
+            out.push(255);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::bus::Bus;
+    use cpu::cartridge::Cartridge;
+    use cpu::ram::Ram;
+
+    // sends a command bit by bit like the games do
+    fn send(ram: &mut Ram, command: u8, args: &[u8]) {
+        let mut packet = [0; 16];
+        packet[0] = command << 3 | 1;
+        packet[1..=args.len()].copy_from_slice(args);
+        ram.write(0xff00, 0x00);
+        ram.write(0xff00, 0x30);
+        for i in 0..=128 {
+            // the stop bit is 0
+            let bit = i < 128 && packet[i / 8] >> (i % 8) & 1 != 0;
+            ram.write(0xff00, if bit { 0x10 } else { 0x20 });
+            ram.write(0xff00, 0x30);
+        }
+    }
+
+    // shows 4KB as tiles for a *_TRN
+    fn show(ram: &mut Ram, data: &[u8]) {
+        for (tile, bytes) in data.chunks(16).enumerate() {
+            let (tx, ty) = (tile % 20, tile / 20);
+            for row in 0..8 {
+                for column in 0..8 {
+                    let lo = (bytes[row * 2] >> (7 - column)) & 1;
+                    let hi = (bytes[row * 2 + 1] >> (7 - column)) & 1;
+                    ram.video.back_buffer[(ty * 8 + row) * 160 + tx * 8 + column] = lo | hi << 1;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sgb_commands() {
+        let mut ram = Ram::origin(Cartridge::default());
+        ram.sgb.enabled = true;
+
+        // PAL01
+        send(
+            &mut ram,
+            0x00,
+            &[0x11, 0x00, 1, 0, 2, 0, 3, 0, 4, 0, 5, 0, 6, 0],
+        );
+        assert_eq!(ram.sgb.palettes[0], [0x11, 1, 2, 3]);
+        assert_eq!(ram.sgb.palettes[1], [0x11, 4, 5, 6]);
+        assert_eq!(ram.sgb.palettes[3][0], 0x11, "shared color 0");
+
+        // ATTR_BLK, only the inside set colors the border too
+        send(&mut ram, 0x04, &[1, 0x01, 0x02, 1, 1, 3, 3]);
+        assert_eq!(ram.sgb.attributes[CELLS_X + 1], 2);
+        assert_eq!(ram.sgb.attributes[2 * CELLS_X + 2], 2);
+        assert_eq!(ram.sgb.attributes[4 * CELLS_X + 4], 0);
+
+        // MLT_REQ, the joypad id is read with both lines high
+        send(&mut ram, 0x11, &[1]);
+        assert_eq!(ram.read(0xff00), 0xff);
+        ram.write(0xff00, 0x10);
+        ram.write(0xff00, 0x30);
+        assert_eq!(ram.read(0xff00), 0xfe);
+        ram.write(0xff00, 0x10);
+        ram.write(0xff00, 0x30);
+        assert_eq!(ram.read(0xff00), 0xff);
+        send(&mut ram, 0x11, &[0]);
+
+        // PAL_TRN takes the next frame, all in shade 3 gives white palettes
+        send(&mut ram, 0x0b, &[]);
+        ram.video.back_buffer = [3; 160 * 144];
+        ram.sgb.vblank(&ram.video);
+        send(&mut ram, 0x0a, &[5, 0, 5, 0, 5, 0, 5, 0]);
+        ram.sgb.vblank(&ram.video);
+        let frame = ram.sgb.frame(false);
+        assert_eq!(frame.len(), WIDTH * HEIGHT * 4);
+        let at = |x: usize, y: usize| frame[(y * WIDTH + x) * 4..(y * WIDTH + x) * 4 + 3].to_vec();
+        assert_eq!(at(SCREEN_X, SCREEN_Y), [255, 255, 255]);
+
+        // MASK_EN black
+        send(&mut ram, 0x17, &[2]);
+        ram.sgb.vblank(&ram.video);
+        let frame = ram.sgb.frame(false);
+        let i = (SCREEN_Y * WIDTH + SCREEN_X) * 4;
+        assert_eq!(frame[i..i + 3], [0, 0, 0]);
+    }
+
+    #[test]
+    fn sgb_attributes() {
+        let mut ram = Ram::origin(Cartridge::default());
+        ram.sgb.enabled = true;
+        let cell = |ram: &Ram, x: usize, y: usize| ram.sgb.attributes[y * CELLS_X + x];
+
+        // ATTR_LIN, line 3 in palette 1 then column 5 in palette 2
+        send(&mut ram, 0x05, &[2, 0x80 | 0x20 | 3, 0x40 | 5]);
+        assert_eq!(cell(&ram, 0, 3), 1);
+        assert_eq!(cell(&ram, 19, 3), 1);
+        assert_eq!(cell(&ram, 5, 3), 2, "the column comes last");
+        assert_eq!(cell(&ram, 5, 17), 2);
+        assert_eq!(cell(&ram, 4, 4), 0);
+
+        // ATTR_DIV, horizontal at line 9: 2 above, 3 on it, 1 below
+        send(&mut ram, 0x06, &[0x40 | 3 << 4 | 2 << 2 | 1, 9]);
+        assert_eq!(cell(&ram, 7, 0), 2);
+        assert_eq!(cell(&ram, 7, 8), 2);
+        assert_eq!(cell(&ram, 7, 9), 3);
+        assert_eq!(cell(&ram, 7, 10), 1);
+        // vertical at column 2
+        send(&mut ram, 0x06, &[3 << 4 | 1 << 2 | 2, 2]);
+        assert_eq!(cell(&ram, 1, 9), 1);
+        assert_eq!(cell(&ram, 2, 9), 3);
+        assert_eq!(cell(&ram, 19, 9), 2);
+
+        // ATTR_CHR, 2 bits a cell from the left, wrapping to the next line
+        send(&mut ram, 0x07, &[18, 2, 4, 0, 0, 0b11_10_01_00]);
+        assert_eq!(cell(&ram, 18, 2), 3);
+        assert_eq!(cell(&ram, 19, 2), 2);
+        assert_eq!(cell(&ram, 0, 3), 1);
+        assert_eq!(cell(&ram, 1, 3), 0);
+        assert_eq!(cell(&ram, 2, 3), 3, "past the count");
+        // from the top, wrapping to the next column
+        send(&mut ram, 0x07, &[5, 17, 2, 0, 1, 0b0110_0000]);
+        assert_eq!(cell(&ram, 5, 17), 1);
+        assert_eq!(cell(&ram, 6, 0), 2);
+    }
+
+    #[test]
+    fn sgb_border() {
+        let mut ram = Ram::origin(Cartridge::default());
+        ram.sgb.enabled = true;
+
+        // CHR_TRN: tile 1 has color 5 (planes 0 and 2) on its top left pixel,
+        // tile 0x81 from the second half color 10 on its bottom right one
+        let mut tiles = vec![0; 0x1000];
+        tiles[32] = 0x80;
+        tiles[32 + 16] = 0x80;
+        send(&mut ram, 0x13, &[0]);
+        show(&mut ram, &tiles);
+        ram.sgb.vblank(&ram.video);
+        let mut tiles = vec![0; 0x1000];
+        tiles[32 + 15] = 0x01;
+        tiles[32 + 31] = 0x01;
+        send(&mut ram, 0x13, &[1]);
+        show(&mut ram, &tiles);
+        ram.sgb.vblank(&ram.video);
+        assert_eq!(ram.sgb.border_pixel(1, 0, 0), 5);
+        assert_eq!(ram.sgb.border_pixel(1, 1, 0), 0);
+        assert_eq!(ram.sgb.border_pixel(0x81, 7, 7), 10);
+
+        // PCT_TRN: the map then palettes 4-7 at 0x800
+        let mut map = vec![0; 0x1000];
+        let mut entry = |i: usize, v: u16| {
+            map[i * 2] = v as u8;
+            map[i * 2 + 1] = (v >> 8) as u8;
+        };
+        // tile 1 with palette 5
+        entry(0, 0x0401);
+        // flipped both ways
+        entry(1, 0xc401);
+        entry(2, 0x0081);
+        // palette 5 color 5 red, palette 4 color 10 blue
+        entry(0x400 + 16 + 5, 0x001f);
+        entry(0x400 + 10, 0x7c00);
+        send(&mut ram, 0x14, &[]);
+        show(&mut ram, &map);
+        ram.sgb.vblank(&ram.video);
+        assert_eq!(ram.sgb.border_map[1], 0xc401);
+        assert_eq!(ram.sgb.border_palettes[1][5], 0x001f);
+
+        let frame = ram.sgb.frame(true);
+        let at = |x: usize, y: usize| frame[(y * WIDTH + x) * 4..(y * WIDTH + x) * 4 + 3].to_vec();
+        let backdrop = rgb555_to_rgb(ram.sgb.palettes[0][0]).to_vec();
+        assert_eq!(at(0, 0), [255, 0, 0]);
+        assert_eq!(at(1, 0), backdrop, "color 0 is transparent");
+        assert_eq!(at(15, 7), [255, 0, 0]);
+        assert_eq!(at(8, 0), backdrop);
+        assert_eq!(at(23, 7), [0, 0, 255]);
+    }
+}
@@ -1,9 +1,18 @@
 use cpu::ram::io::*;
 use cpu::ram::Ram;
-use std::cmp::Ordering;
 use std::panic;
+
+pub mod cgb;
+pub mod compat;
+mod fifo;
+pub mod palette;
+use self::cgb::*;
+use self::fifo::Fifo;
+pub use self::fifo::Renderer;
+use self::palette::*;
+
 const VIDEO_DEBUG: bool = true;
-#[derive(Copy, Clone, Eq)]
+#[derive(Copy, Clone)]
 pub struct Sprite {
     pub y: u8,
     pub x: u8,
@@ -12,24 +21,11 @@ pub struct Sprite {
     y_flip: bool,
     x_flip: bool,
     palette: bool,
-    //vrambank CGB
-    //palette CGB
-}
-
-impl Ord for Sprite {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.x.cmp(&other.x)
-    }
-}
-impl PartialOrd for Sprite {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
-    }
-}
-impl PartialEq for Sprite {
-    fn eq(&self, other: &Self) -> bool {
-        self.x == other.x
-    }
+    // the attribute byte as written, read back by the cpu, on the CGB bit 3
+    // is the vram bank and bits 0-2 the palette
+    attr: u8,
+    // position in oam, set by the oam scan
+    index: u8,
 }
 
 impl Sprite {
@@ -42,6 +38,8 @@ impl Sprite {
             y_flip: false,
             x_flip: false,
             palette: false,
+            attr: 0,
+            index: 0,
         }
     }
     pub fn write_y(&mut self, v: u8) {
@@ -54,6 +52,7 @@ impl Sprite {
         self.tile = v;
     }
     pub fn write_attr(&mut self, v: u8) {
+        self.attr = v;
         self.behind_bg = bit(v, 7);
         self.y_flip = bit(v, 6);
         self.x_flip = bit(v, 5);
@@ -68,6 +67,14 @@ impl Sprite {
             _ => panic!("impossible"),
         }
     }
+    pub fn read(&self, a: u16) -> u8 {
+        match a & 3 {
+            0 => self.y,
+            1 => self.x,
+            2 => self.tile,
+            _ => self.attr,
+        }
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -75,12 +82,30 @@ pub struct Pixel {
     behind_bg: bool,
     palette: bool,
     color: u8,
+    // CGB palette and oam index of the sprite
+    cgb_palette: u8,
+    index: u8,
+}
+
+const TRANSPARENT: Pixel = Pixel {
+    behind_bg: false,
+    palette: false,
+    color: 0,
+    cgb_palette: 0,
+    index: 0,
+};
+
+// a background or window pixel with the CGB attribute of its tile (0 on the DMG)
+#[derive(Clone, Copy)]
+pub struct BgPixel {
+    color: u8,
+    attr: u8,
 }
 
 #[derive(Clone, Copy)]
 pub struct WindowPixel {
     transparent: bool,
-    color: u8,
+    pixel: BgPixel,
 }
 
 #[derive(Derivative)]
@@ -89,13 +114,43 @@ pub struct Video {
     line_clock: u16,
     line: u8,
     window_line: u8,
+    pub renderer: Renderer,
     #[derivative(Debug = "ignore")]
-    pub vram: [u8; 0x2000],
+    fifo: Fifo,
+    // line_clock at which mode 3 ends on this line
+    mode_3_end: u16,
+    // after the lcd is enabled line 0 has no oam scan and is shorter, the
+    // first frame is not shown
+    first_line: bool,
+    first_frame: bool,
+    // WY matched LY on a line of this frame
+    window_y_hit: bool,
+    // the window started on the last pixel (WX=166), it fills the next line
+    window_wrap: bool,
+    // both banks, the second one only used on the CGB
     #[derivative(Debug = "ignore")]
-    tiles: [[u8; 8 * 8]; 0x180],
+    pub vram: [u8; 0x4000],
+    #[derivative(Debug = "ignore")]
+    tiles: [[u8; 8 * 8]; 0x300],
+    // CGB mode, the CGB registers and colors
+    pub cgb: bool,
+    // the CGB or AGB ppu, also in DMG mode: no oam bug or STAT write quirk
+    pub cgb_hardware: bool,
+    // offset of the bank selected by VBK
+    vram_bank: usize,
+    #[derivative(Debug = "ignore")]
+    pub bg_palettes: CgbPalettes,
+    #[derivative(Debug = "ignore")]
+    pub obj_palettes: CgbPalettes,
+    object_priority_x: bool,
+    // RGB555 colors of the pixels in CGB mode
+    #[derivative(Debug = "ignore")]
+    cgb_back_buffer: [u16; 144 * 160],
 
+    // shade and source of each pixel, see palette.rs
     #[derivative(Debug = "ignore")]
     pub back_buffer: [u8; 144 * 160],
+    pub palette: Palette,
     #[derivative(Debug = "ignore")]
     oam: [Sprite; 40],
 
@@ -117,6 +172,10 @@ pub struct Video {
     enable_mode_1_vblank_check: bool,
     enable_mode_0_hblank_check: bool,
     signal_ly_lcy_comparison: bool,
+    // state of the stat interrupt line
+    stat_irq: bool,
+    // a stat write happened this clock
+    stat_write: bool,
 
     line_compare: u8,
 
@@ -135,17 +194,38 @@ pub struct Video {
     window_scroll_y: u8,
 }
 
+const END_MODE_0: u16 = 1;
+const END_MODE_2: u16 = 21;
+// mode 3 has this fixed end with the scanline renderer only
+const END_MODE_3: u16 = 64;
+const LINE_CLOCKS: u16 = 114;
+
 impl Video {
     pub fn origin() -> Video {
         Video {
             oam: [Sprite::origin(); 40],
             back_buffer: [0; 144 * 160],
-            vram: [0; 0x2000],
-            tiles: [[0; 8 * 8]; 0x180],
+            palette: Palette::gray(),
+            vram: [0; 0x4000],
+            tiles: [[0; 8 * 8]; 0x300],
+            cgb: false,
+            cgb_hardware: false,
+            vram_bank: 0,
+            bg_palettes: CgbPalettes::origin(),
+            obj_palettes: CgbPalettes::origin(),
+            object_priority_x: false,
+            cgb_back_buffer: [0; 144 * 160],
 
             line_clock: 0,
             line: 0,
             window_line: 0,
+            renderer: Renderer::Fifo,
+            fifo: Fifo::origin(),
+            mode_3_end: END_MODE_3,
+            first_line: false,
+            first_frame: false,
+            window_y_hit: false,
+            window_wrap: false,
 
             updated_map_1: false,
             updated_map_2: false,
@@ -164,6 +244,8 @@ impl Video {
             enable_mode_1_vblank_check: false,
             enable_mode_2_oam_check: false,
             signal_ly_lcy_comparison: false,
+            stat_irq: false,
+            stat_write: false,
             line_compare: 0,
             scroll_x: 0,
             scroll_y: 0,
@@ -189,23 +271,31 @@ impl Video {
     fn draw_window(&mut self) -> [WindowPixel; 160] {
         let mut out_line = [WindowPixel {
             transparent: true,
-            color: 0,
+            pixel: BgPixel { color: 0, attr: 0 },
         }; 160];
-        if self.enable_window == false
-            || self.window_scroll_x >= 167
-            || self.window_scroll_y > self.line
-        {
+        let wrap = std::mem::replace(&mut self.window_wrap, false);
+        if self.enable_window == false || !self.window_y_hit {
             return out_line;
         }
         let tile_map: u16 = if self.window_tile_map { 0x1C00 } else { 0x1800 };
         let mut screen_x;
         let mut window_x;
-        if self.window_scroll_x <= 7 {
+        if wrap {
+            screen_x = 0;
+            window_x = 0;
+        } else if self.window_scroll_x >= 167 {
+            return out_line;
+        } else if self.window_scroll_x == 0 {
+            // moves with the fine scroll
+            screen_x = 0;
+            window_x = 7 + (self.scroll_x % 8) as usize;
+        } else if self.window_scroll_x <= 7 {
             screen_x = 0;
             window_x = 7 - self.window_scroll_x as usize;
         } else {
             screen_x = (self.window_scroll_x - 7) as usize;
             window_x = 0;
+            self.window_wrap = self.window_scroll_x == 166;
         };
 
         let tile_line = self.window_line / 8;
@@ -218,16 +308,17 @@ impl Video {
             let map_offset = map_offset as usize;
 
             let tile = self.vram[map_offset];
+            let attr = self.map_attr(map_offset);
             //let (l,h) = self.read_tile(tile, tile_sub_line as u16);
             'inner: loop {
                 //let l_bit = (l>>(7-tile_sub_column)) & 1;
                 //let h_bit = (h>>(7-tile_sub_column)) & 1;
                 //let color = l_bit + h_bit * 2;
-                let color = self.get_tile(tile, (tile_sub_line * 8 + tile_sub_column as u8) as u16);
+                let color = self.bg_tile_pixel(tile, attr, tile_sub_line, tile_sub_column as u8);
 
                 out_line[screen_x] = WindowPixel {
                     transparent: false,
-                    color,
+                    pixel: BgPixel { color, attr },
                 };
                 /* self.back_buffer[
                 self.line as usize*160+screen_x] =
@@ -248,26 +339,41 @@ impl Video {
         out_line
     }
 
-    fn get_tile_1(&self, tile: u8, pixel: u16) -> u8 {
-        self.tiles[tile as usize][pixel as usize]
-    }
-
-    fn get_tile_0(&self, tile: u8, pixel: u16) -> u8 {
-        self.tiles[(tile ^ 0x80) as usize + 128][pixel as usize]
+    // the CGB attribute of the map entry at `offset`
+    fn map_attr(&self, offset: usize) -> u8 {
+        if self.cgb {
+            self.vram[0x2000 + offset]
+        } else {
+            0
+        }
     }
 
-    fn get_tile(&self, tile: u8, pixel: u16) -> u8 {
-        if self.tile_set {
-            self.get_tile_1(tile, pixel)
+    // a pixel of a background or window tile, in the bank and flips of `attr`
+    fn bg_tile_pixel(&self, tile: u8, attr: u8, row: u8, column: u8) -> u8 {
+        let row = if attr & ATTR_Y_FLIP != 0 { 7 - row } else { row };
+        let column = if attr & ATTR_X_FLIP != 0 {
+            7 - column
         } else {
-            self.get_tile_0(tile, pixel)
-        }
+            column
+        };
+        let bank = if attr & ATTR_BANK != 0 {
+            BANK_1_TILES
+        } else {
+            0
+        };
+        let tile = if self.tile_set {
+            tile as usize
+        } else {
+            (tile ^ 0x80) as usize + 128
+        };
+        self.tiles[bank + tile][(row * 8 + column) as usize]
     }
 
-    fn draw_bg(&mut self) -> [u8; 160] {
-        let mut out_line: [u8; 160] = [0; 160];
-        if self.enable_background == false {
-            self.back_buffer = [255; 144 * 160];
+    fn draw_bg(&mut self) -> [BgPixel; 160] {
+        let mut out_line = [BgPixel { color: 0, attr: 0 }; 160];
+        // the CGB keeps the background, it only loses its priority
+        if self.enable_background == false && !self.cgb {
+            self.back_buffer = [SOURCE_BG; 144 * 160];
             return out_line;
         }
 
@@ -288,15 +394,21 @@ impl Video {
             let mut bg_tile_sub_column = (bg_column % 256) % 8;
             let bg_map_offset: u16 = bg_tile_map + bg_tile_line * 32 + bg_tile_column;
             let tile = self.vram[bg_map_offset as usize];
+            let attr = self.map_attr(bg_map_offset as usize);
             //let (l,h) = self.read_tile(tile,bg_tile_sub_line);
 
             'inner: loop {
                 //let l_bit = (l>>(7-bg_tile_sub_column)) & 1;
                 //let h_bit = (h>>(7-bg_tile_sub_column)) & 1;
                 //let color = l_bit + h_bit * 2;
-                let color = self.get_tile(tile, bg_tile_sub_line * 8 + bg_tile_sub_column);
+                let color = self.bg_tile_pixel(
+                    tile,
+                    attr,
+                    bg_tile_sub_line as u8,
+                    bg_tile_sub_column as u8,
+                );
                 // println!("line {} x {}",ram.video.line,x);
-                out_line[x] = color;
+                out_line[x] = BgPixel { color, attr };
                 /*                self.back_buffer[
                                     self.line as usize*160+x] =
                                     self.background_palette[color as usize];
@@ -314,66 +426,49 @@ impl Video {
         }
         out_line
     }
+    // the oam search keeps the first 10 sprites of the line by oam index,
+    // those outside the screen horizontally included
+    fn oam_scan(&self) -> Vec<Sprite> {
+        let height = if self.sprite_size { 16 } else { 8 };
+        let top = self.line + 16;
+        self.oam
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| s.y <= top && s.y > top - height)
+            .take(10)
+            .map(|(i, s)| Sprite {
+                index: i as u8,
+                ..*s
+            })
+            .collect()
+    }
+
     pub fn draw_sprite_both(&mut self) -> [Pixel; 160] {
-        let yoffset;
-        if self.sprite_size {
-            yoffset = 16;
-        } else {
-            yoffset = 8;
-        }
-        let mut line = [Pixel {
-            behind_bg: false,
-            palette: false,
-            color: 0,
-        }; 160];
+        let mut line = [TRANSPARENT; 160];
         if self.enable_sprites == false {
             return line;
         }
 
-        let mut list: Vec<Sprite> = self
-            .oam
-            .iter()
-            .filter(|s| s.y <= self.line + 16 && s.y > self.line + 16 - yoffset)
-            .copied()
-            .collect();
-        list.sort_by(|a, b| b.x.cmp(&a.x));
-        //TODO fix order of sprites from front to back
+        // on the DMG the smaller x is in front, then the smaller oam index, the
+        // sort is stable and the scan keeps the oam order, the CGB only looks
+        // at the oam index
+        let mut list = self.oam_scan();
+        if self.dmg_sprite_priority() {
+            list.sort_by_key(|s| s.x);
+        }
         for f in list.iter() {
-            let mut tile_line = self.line as i16 - (f.y as i16 - 16);
-            let mut tile;
-            if self.sprite_size {
-                if tile_line < 8 {
-                    //upper tile
-                    tile = f.tile & 0xfe;
-                } else {
-                    //lower tile
-                    tile_line -= 8;
-                    tile = f.tile | 0x01;
-                }
-                if f.y_flip {
-                    tile ^= 0x01;
-                }
-            } else {
-                tile = f.tile;
-            }
-
-            let tile_line = if f.y_flip { 7 - tile_line } else { tile_line };
-
-            //            println!("16 tile {} {} {:02x} {}",f.x,f.y,tile,tile_line);
-            for i in f.x.saturating_sub(8)..std::cmp::min(f.x, 159) {
-                let tile_column = i + 8 - f.x;
-                let tile_column = if f.x_flip {
-                    7 - tile_column
-                } else {
-                    tile_column
-                };
-                let color = self.get_tile_1(tile, (tile_line * 8 + tile_column as i16) as u16);
+            let row = self.sprite_row(f);
+            for i in f.x.saturating_sub(8)..std::cmp::min(f.x, 160) {
+                let color = row[(i + 8 - f.x) as usize];
                 //                println!("pixel {} {} {}",self.line,i,color);
-                if color != 0 {
+                // a transparent pixel shows the sprites behind
+                if color != 0 && line[i as usize].color == 0 {
                     line[i as usize] = Pixel {
                         behind_bg: f.behind_bg,
                         palette: f.palette,
                         color,
+                        cgb_palette: f.attr & 7,
+                        index: f.index,
                     };
                 }
             }
@@ -381,35 +476,86 @@ impl Video {
         line
     }
 
+    // the 8 pixels of a sprite on the current line, left to right
+    fn sprite_row(&self, f: &Sprite) -> [u8; 8] {
+        let mut tile_line = self.line as i16 - (f.y as i16 - 16);
+        let mut tile;
+        if self.sprite_size {
+            if tile_line < 8 {
+                //upper tile
+                tile = f.tile & 0xfe;
+            } else {
+                //lower tile
+                tile_line -= 8;
+                tile = f.tile | 0x01;
+            }
+            if f.y_flip {
+                tile ^= 0x01;
+            }
+        } else {
+            tile = f.tile;
+        }
+
+        let tile_line = if f.y_flip { 7 - tile_line } else { tile_line };
+
+        //            println!("16 tile {} {} {:02x} {}",f.x,f.y,tile,tile_line);
+        let bank = if self.cgb && f.attr & ATTR_BANK != 0 {
+            BANK_1_TILES
+        } else {
+            0
+        };
+        let mut row = [0; 8];
+        for (tile_column, color) in row.iter_mut().enumerate() {
+            let tile_column = if f.x_flip {
+                7 - tile_column
+            } else {
+                tile_column
+            };
+            *color = self.tiles[bank + tile as usize][(tile_line * 8 + tile_column as i16) as usize];
+        }
+        row
+    }
+
     pub fn draw_line(&mut self) {
         let bg = self.draw_bg();
         let sprites = self.draw_sprite_both();
         let win = self.draw_window();
 
         for (i, (b, s, w)) in izip!(bg.iter(), sprites.iter(), win.iter()).enumerate() {
-            let index = self.line as usize * 160 + i;
+            let px = if w.transparent { *b } else { w.pixel };
+            self.put_pixel(i, px, *s);
+        }
+    }
+
+    fn dmg_sprite_priority(&self) -> bool {
+        !self.cgb || self.object_priority_x
+    }
+
+    // mixes the background and sprite pixels at x of the current line
+    fn put_pixel(&mut self, x: usize, b: BgPixel, s: Pixel) {
+        let index = self.line as usize * 160 + x;
+        if self.cgb {
+            // without LCDC bit 0 the sprites are always in front, else the
+            // priority bits of the sprite and of the tile both count
+            let sprite = s.color != 0
+                && (!self.enable_background
+                    || b.color == 0
+                    || (!s.behind_bg && b.attr & ATTR_PRIORITY == 0));
+            self.cgb_back_buffer[index] = if sprite {
+                self.obj_palettes.color(s.cgb_palette, s.color)
+            } else {
+                self.bg_palettes.color(b.attr & 7, b.color)
+            };
+        } else {
             let pal = if s.palette {
                 &self.sprite_palette_1
             } else {
                 &self.sprite_palette_0
             };
-            let mut px = *b;
-            if !w.transparent {
-                px = w.color
-            };
-
-            self.back_buffer[index] = if s.behind_bg {
-                if px == 0 && s.color != 0 {
-                    pal[s.color as usize - 1]
-                } else {
-                    self.background_palette[px as usize]
-                }
+            self.back_buffer[index] = if s.color != 0 && !(s.behind_bg && b.color != 0) {
+                pal[s.color as usize - 1]
             } else {
-                if s.color != 0 {
-                    pal[s.color as usize - 1]
-                } else {
-                    self.background_palette[px as usize]
-                }
+                self.background_palette[b.color as usize]
             };
         }
     }
@@ -418,73 +564,71 @@ impl Video {
         let mut outvblank = Interrupt::None;
         let mut outlcdc = Interrupt::None;
 
-        const END_MODE_0: u16 = 1;
-        const END_MODE_2: u16 = 21;
-        const END_MODE_3: u16 = 64;
-        //        const END_MODE_0: u16 = 115;
         if ram.video.enable_lcd {
             ram.video.line_clock += 1;
 
             match ram.video.line {
                 0..=143 => {
                     //screen
-                    match ram.video.line_clock {
-                        END_MODE_2 => {
-                            //println!("mode3");
-                            ram.video.draw_line();
-                        }
-                        END_MODE_3 => {
-                            //println!("mode0");
-                            if ram.video.enable_mode_0_hblank_check {
-                                outlcdc = Interrupt::LcdcStatus;
+                    let video = &mut ram.video;
+                    if video.line_clock == END_MODE_0 && video.line == video.window_scroll_y {
+                        video.window_y_hit = true;
+                    }
+                    if video.line_clock == END_MODE_2 {
+                        //println!("mode3");
+                        match video.renderer {
+                            Renderer::Scanline => {
+                                video.draw_line();
+                                video.mode_3_end = END_MODE_3;
                             }
-                        }
-                        END_MODE_0 => {
-                            //println!("mode2");
-                            if ram.video.enable_mode_2_oam_check {
-                                outlcdc = Interrupt::LcdcStatus;
+                            Renderer::Fifo => {
+                                video.fifo_start_line();
+                                video.mode_3_end = u16::MAX;
                             }
                         }
-                        _ => {}
+                    }
+                    if video.fifo.active && video.fifo_dots(4) {
+                        video.mode_3_end = video.line_clock + 1;
                     }
                 }
                 144 => {
                     if ram.video.line_clock == END_MODE_0 {
                         outvblank = Interrupt::VBlank;
-
-                        if ram.video.enable_mode_1_vblank_check && !ram.interrupt.enable_vblank {
-                            outlcdc = Interrupt::LcdcStatus;
-                        }
                     }
                 }
-                145..=152 => {
+                145..=153 => {
                     // vblank
                 }
-                153 => {
-                    if ram.video.line_clock == END_MODE_3 {
-                        ram.video.line = 0;
-                        ram.video.window_line = 0;
-                        ram.video.line_clock = 0;
-                        outvblank = Interrupt::VBlankEnd;
-                        println!("{:?}", ram.video);
-                    }
-                    // shorter line
-                }
                 _ => unreachable!("yes"),
             }
-            if ram.video.line_clock == 1 {
-                ram.video.signal_ly_lcy_comparison = ram.video.line == ram.video.line_compare;
-                println!(
-                    "lylyc is {}  {} {}",
-                    ram.video.signal_ly_lcy_comparison, ram.video.line, ram.video.line_compare
-                );
-                if ram.video.signal_ly_lcy_comparison && ram.video.enable_ly_lcy_check {
-                    outlcdc = Interrupt::LcdcStatus;
-                }
+
+            let video = &mut ram.video;
+            video.signal_ly_lcy_comparison = video.read_line() == video.line_compare;
+            // the sources share one line, only its rising edge interrupts
+            let stat_line = video.stat_line();
+            if (stat_line || video.stat_write) && !video.stat_irq {
+                outlcdc = Interrupt::LcdcStatus;
             }
-            if ram.video.line_clock == 115 {
-                ram.video.line_clock = 0;
-                ram.video.line += 1;
+            video.stat_irq = stat_line;
+            video.stat_write = false;
+
+            let length = if video.first_line {
+                LINE_CLOCKS - 1
+            } else {
+                LINE_CLOCKS
+            };
+            if video.line_clock == length {
+                video.line_clock = 0;
+                video.first_line = false;
+                if video.line == 153 {
+                    video.line = 0;
+                    video.window_line = 0;
+                    video.window_y_hit = false;
+                    video.first_frame = false;
+                    outvblank = Interrupt::VBlankEnd;
+                } else {
+                    video.line += 1;
+                }
             }
         }
         /*
@@ -544,11 +688,21 @@ impl Video {
         }*/
         (outlcdc, outvblank)
     }
+    // the lcd already running at `line_clock` of `line`, as a boot rom leaves it
+    pub fn resume_at(&mut self, line: u8, line_clock: u16) {
+        self.line = line;
+        self.line_clock = line_clock;
+        self.first_line = false;
+        self.first_frame = false;
+        self.signal_ly_lcy_comparison = self.read_line() == self.line_compare;
+        self.stat_irq = self.stat_line();
+    }
     pub fn write_control(&mut self, v: u8) {
         if VIDEO_DEBUG {
             println!("write lcd control {:02x}", v);
         }
         //let v = bit_split(v);
+        let was_enabled = self.enable_lcd;
         self.enable_lcd = bit(v, 7);
         self.window_tile_map = bit(v, 6);
         self.enable_window = bit(v, 5);
@@ -557,9 +711,19 @@ impl Video {
         self.sprite_size = bit(v, 2);
         self.enable_sprites = bit(v, 1);
         self.enable_background = bit(v, 0);
-        if !self.enable_lcd {
+        if was_enabled && !self.enable_lcd {
+            // LY and the mode read 0 and the screen turns white
             self.line = 0;
             self.line_clock = 0;
+            self.fifo.active = false;
+            self.stat_irq = false;
+            self.back_buffer = [SOURCE_BG; 144 * 160];
+        } else if !was_enabled && self.enable_lcd {
+            self.first_line = true;
+            self.first_frame = true;
+            self.window_line = 0;
+            self.window_y_hit = false;
+            self.window_wrap = false;
         }
     }
 
@@ -580,6 +744,11 @@ impl Video {
         if VIDEO_DEBUG {
             println!("writing status {:x}", v);
         }
+        // the DMG sees every source enabled for the clock of the write, this
+        // interrupts outside mode 3 or on LY=LYC if the line was low
+        self.stat_write = !self.cgb_hardware
+            && self.enable_lcd
+            && (self.get_video_mode() != 3 || self.signal_ly_lcy_comparison);
         let v = bit_split(v);
         self.enable_ly_lcy_check = v[6];
         self.enable_mode_2_oam_check = v[5];
@@ -593,13 +762,46 @@ impl Video {
             1
         } else {
             match self.line_clock {
-                1..=20 => 2,
-                21..=63 => 3,
+                1..=20 if !self.first_line => 2,
+                c if (END_MODE_2..self.mode_3_end).contains(&c) => 3,
                 _ => 0,
             }
         }
     }
 
+    // the mode 2 source also rises at the start of line 144
+    fn stat_line(&self) -> bool {
+        let mode = self.get_video_mode();
+        (self.enable_ly_lcy_check && self.signal_ly_lcy_comparison)
+            || (self.enable_mode_0_hblank_check && mode == 0)
+            || (self.enable_mode_1_vblank_check && mode == 1)
+            || (self.enable_mode_2_oam_check
+                && (mode == 2 || (self.line == 144 && self.line_clock == END_MODE_0)))
+    }
+
+    // back_buffer in the colors of `palette`
+    pub fn frame(&self) -> Vec<u8> {
+        self.frame_with(&self.palette)
+    }
+
+    // the frame with the DMG shades in `palette`
+    pub fn frame_with(&self, palette: &Palette) -> Vec<u8> {
+        if self.cgb {
+            self.cgb_frame()
+        } else {
+            palette.rgba(&self.back_buffer)
+        }
+    }
+
+    pub fn lcd_enabled(&self) -> bool {
+        self.enable_lcd
+    }
+
+    // nothing is shown while the lcd is off and on the frame after enabling it
+    pub fn lcd_off(&self) -> bool {
+        !self.enable_lcd || self.first_frame
+    }
+
     pub fn read_status(&self) -> u8 {
         if VIDEO_DEBUG {
             println!("read status {} {}", self.line, self.line_clock);
@@ -658,8 +860,13 @@ impl Video {
         self.window_scroll_x
     }
 
+    // LY already reads 0 after the first clock of line 153
     pub fn read_line(&self) -> u8 {
-        self.line
+        if self.line == 153 && self.line_clock > 1 {
+            0
+        } else {
+            self.line
+        }
     }
     pub fn read_line_compare(&self) -> u8 {
         self.line_compare
@@ -675,52 +882,104 @@ impl Video {
         self.background_palette_bits
     }
     pub fn write_background_palette(&mut self, v: u8) {
-        //let base = [0,75,140,255];
-        let base = [255, 140, 75, 0];
         self.background_palette_bits = v;
-        self.background_palette[0] = base[(v & 3) as usize];
-        self.background_palette[1] = base[((v >> 2) & 3) as usize];
-        self.background_palette[2] = base[((v >> 4) & 3) as usize];
-        self.background_palette[3] = base[((v >> 6) & 3) as usize];
+        self.background_palette[0] = SOURCE_BG | (v & 3);
+        self.background_palette[1] = SOURCE_BG | ((v >> 2) & 3);
+        self.background_palette[2] = SOURCE_BG | ((v >> 4) & 3);
+        self.background_palette[3] = SOURCE_BG | ((v >> 6) & 3);
     }
     pub fn read_sprite_palette_0(&self) -> u8 {
         self.sprite_palette_0_bits
     }
     pub fn write_sprite_palette_0(&mut self, v: u8) {
-        let base = [255, 140, 75, 0];
         self.sprite_palette_0_bits = v;
-        self.sprite_palette_0[0] = base[((v >> 2) & 3) as usize];
-        self.sprite_palette_0[1] = base[((v >> 4) & 3) as usize];
-        self.sprite_palette_0[2] = base[((v >> 6) & 3) as usize];
+        self.sprite_palette_0[0] = SOURCE_OBP0 | ((v >> 2) & 3);
+        self.sprite_palette_0[1] = SOURCE_OBP0 | ((v >> 4) & 3);
+        self.sprite_palette_0[2] = SOURCE_OBP0 | ((v >> 6) & 3);
     }
     pub fn read_sprite_palette_1(&self) -> u8 {
         self.sprite_palette_1_bits
     }
     pub fn write_sprite_palette_1(&mut self, v: u8) {
-        let base = [255, 140, 75, 0];
         self.sprite_palette_1_bits = v;
-        self.sprite_palette_1[0] = base[((v >> 2) & 3) as usize];
-        self.sprite_palette_1[1] = base[((v >> 4) & 3) as usize];
-        self.sprite_palette_1[2] = base[((v >> 6) & 3) as usize];
+        self.sprite_palette_1[0] = SOURCE_OBP1 | ((v >> 2) & 3);
+        self.sprite_palette_1[1] = SOURCE_OBP1 | ((v >> 4) & 3);
+        self.sprite_palette_1[2] = SOURCE_OBP1 | ((v >> 6) & 3);
     }
-    pub fn write_oam(&mut self, a: u16, v: u8) {
-        let mode = self.get_video_mode();
-        if mode > 1 {
-            println!(
-                "##########################Tried to write to oam in mode{}",
-                mode
-            );
+    // the ppu owns vram during mode 3 and oam during modes 2 and 3, the cpu
+    // then reads 0xff and its writes are lost (checked by Ram, dma is not blocked)
+    pub fn vram_blocked(&self) -> bool {
+        self.enable_lcd && self.get_video_mode() == 3
+    }
+    pub fn oam_blocked(&self) -> bool {
+        self.enable_lcd && self.get_video_mode() >= 2
+    }
+    // DMG oam bug: while the ppu scans oam in mode 2 (one 8 bytes row per
+    // cycle), a cpu access to 0xfe00-0xfeff mixes the scanned row with the
+    // previous ones
+    fn oam_bug_row(&self) -> Option<usize> {
+        if !self.cgb_hardware && self.enable_lcd && self.get_video_mode() == 2 {
+            Some((self.line_clock as usize).saturating_sub(1).min(19))
         } else {
-            println!("$$$$$$$ Write ot oam")
+            None
         }
-        self.oam[(a >> 2) as usize].write(a & 0x3, v);
     }
-    pub fn write_vram(&mut self, a: u16, v: u8) {
-        if self.get_video_mode() == 3 {
-            println!("##########################Tried to write to vram in mode3");
+    fn oam_word(&self, row: usize, i: usize) -> u16 {
+        let a = (row * 8 + i * 2) as u16;
+        self.read_oam(a) as u16 | (self.read_oam(a + 1) as u16) << 8
+    }
+    fn set_oam_word(&mut self, row: usize, i: usize, v: u16) {
+        let a = (row * 8 + i * 2) as u16;
+        self.write_oam(a, v as u8);
+        self.write_oam(a + 1, (v >> 8) as u8);
+    }
+    fn copy_oam_row(&mut self, from: usize, to: usize, first: usize) {
+        for i in first..4 {
+            let v = self.oam_word(from, i);
+            self.set_oam_word(to, i, v);
+        }
+    }
+    pub fn oam_bug_write(&mut self) {
+        if let Some(row) = self.oam_bug_row().filter(|r| *r > 0) {
+            let a = self.oam_word(row, 0);
+            let b = self.oam_word(row - 1, 0);
+            let c = self.oam_word(row - 1, 2);
+            self.set_oam_word(row, 0, ((a ^ c) & (b ^ c)) ^ c);
+            self.copy_oam_row(row - 1, row, 1);
+        }
+    }
+    pub fn oam_bug_read(&mut self) {
+        if let Some(row) = self.oam_bug_row().filter(|r| *r > 0) {
+            let a = self.oam_word(row, 0);
+            let b = self.oam_word(row - 1, 0);
+            let c = self.oam_word(row - 1, 2);
+            self.set_oam_word(row, 0, b | (a & c));
+            self.copy_oam_row(row - 1, row, 1);
+        }
+    }
+    // a read with an increase of its address, outside of the first four rows
+    // and the last one it is a plain read corruption
+    pub fn oam_bug_read_increase(&mut self) {
+        if let Some(row) = self.oam_bug_row().filter(|r| *r >= 4 && *r < 19) {
+            let a = self.oam_word(row - 2, 0);
+            let b = self.oam_word(row - 1, 0);
+            let c = self.oam_word(row, 0);
+            let d = self.oam_word(row - 2, 2);
+            self.set_oam_word(row - 1, 0, (b & (a | c | d)) | (a & c & d));
+            self.copy_oam_row(row - 1, row - 2, 0);
+            self.copy_oam_row(row - 1, row, 0);
         } else {
-            println!("$$$$$$ Write to vram");
+            self.oam_bug_read();
         }
+    }
+    pub fn write_oam(&mut self, a: u16, v: u8) {
+        self.oam[(a >> 2) as usize].write(a & 0x3, v);
+    }
+    pub fn read_oam(&self, a: u16) -> u8 {
+        self.oam[(a >> 2) as usize].read(a & 0x3)
+    }
+    pub fn write_vram(&mut self, a: u16, v: u8) {
+        let tiles = if self.vram_bank == 0 { 0 } else { BANK_1_TILES };
         match a {
             0..=0x17ff => {
                 self.updated_tiles = true;
@@ -729,10 +988,10 @@ impl Video {
                     //low bits
                     let bits = bit_split(v);
                     for i in 0..8 {
-                        self.tiles[a / 16][a % 16 * 4 + i] = if bits[7 - i] {
-                            self.tiles[a / 16][a % 16 * 4 + i] | 1
+                        self.tiles[tiles + a / 16][a % 16 * 4 + i] = if bits[7 - i] {
+                            self.tiles[tiles + a / 16][a % 16 * 4 + i] | 1
                         } else {
-                            self.tiles[a / 16][a % 16 * 4 + i] & !1
+                            self.tiles[tiles + a / 16][a % 16 * 4 + i] & !1
                         };
                     }
                 } else {
@@ -740,24 +999,232 @@ impl Video {
                     let a = a - 1;
                     let bits = bit_split(v);
                     for i in 0..8 {
-                        self.tiles[a / 16][(a % 16) * 4 + i] = if bits[7 - i] {
-                            self.tiles[a / 16][a % 16 * 4 + i] | 2
+                        self.tiles[tiles + a / 16][(a % 16) * 4 + i] = if bits[7 - i] {
+                            self.tiles[tiles + a / 16][a % 16 * 4 + i] | 2
                         } else {
-                            self.tiles[a / 16][a % 16 * 4 + i] & !2
+                            self.tiles[tiles + a / 16][a % 16 * 4 + i] & !2
                         };
                     }
                 }
             }
-            0x1800..=0x1bff => self.updated_map_1 = true,
-            0x1c00..=0x1fff => self.updated_map_2 = true,
+            0x1800..=0x1bff if tiles == 0 => self.updated_map_1 = true,
+            0x1c00..=0x1fff if tiles == 0 => self.updated_map_2 = true,
+            0x1800..=0x1fff => {}
             _ => panic!(),
         }
-        self.vram[a as usize] = v;
+        self.vram[self.vram_bank + a as usize] = v;
     }
     pub fn read_vram(&self, a: u16) -> u8 {
-        if self.get_video_mode() == 3 {
-            println!("##########################Tried to read from vram in mode3");
+        self.vram[self.vram_bank + a as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::cartridge::Cartridge;
+    use cpu::Bus;
+
+    // clocks of a frame, 154 lines of 114
+    pub(super) const FRAME: u32 = 154 * 114;
+
+    pub(super) fn ram() -> Ram {
+        Ram::origin(Cartridge::default())
+    }
+
+    // steps the ppu `clocks` times
+    pub(super) fn run(ram: &mut Ram, clocks: u32) {
+        for clock in 0..clocks {
+            Video::step(ram, clock);
+        }
+    }
+
+    // steps the ppu until `done` holds
+    pub(super) fn run_until(ram: &mut Ram, done: impl Fn(&mut Ram) -> bool) {
+        while !done(ram) {
+            Video::step(ram, 0);
+        }
+    }
+
+    // lines on which the stat interrupt is requested during a frame with `stat`
+    fn stat_interrupts(stat: u8, lyc: u8) -> Vec<u8> {
+        let mut ram = ram();
+        ram.write(0xff40, 0x00);
+        ram.write(0xff45, lyc);
+        ram.write(0xff41, stat);
+        ram.write(0xff40, 0x91);
+        let mut lines = Vec::new();
+        // the first line after enabling is a clock shorter
+        for clock in 0..FRAME - 1 {
+            let line = ram.video.read_line();
+            if let (Interrupt::LcdcStatus, _) = Video::step(&mut ram, clock) {
+                lines.push(line);
+            }
+        }
+        lines
+    }
+
+    #[test]
+    fn stat_irq_line() {
+        // mode 2 follows hblank while the line is still high, only the first
+        // line and each hblank interrupt
+        let lines = stat_interrupts(0x28, 0xff);
+        assert_eq!(lines.len(), 1 + 144);
+        // vblank starts on line 144 while hblank holds the line high (line 0
+        // starts in mode 0 after enabling)
+        assert_eq!(stat_interrupts(0x18, 0xff).len(), 1 + 144);
+        // LY reads 0 after the first clock of line 153, LYC=0 matches there
+        // and holds the line high through line 0
+        assert_eq!(stat_interrupts(0x40, 0x00), vec![0, 153]);
+        assert_eq!(stat_interrupts(0x40, 0x99), vec![153]);
+
+        // writing STAT on the DMG raises the line for a clock outside mode 3
+        let mut ram = ram();
+        ram.write(0xff40, 0x91);
+        ram.write(0xff41, 0x00);
+        let (stat, _) = Video::step(&mut ram, 1);
+        assert!(matches!(stat, Interrupt::LcdcStatus));
+        let (stat, _) = Video::step(&mut ram, 2);
+        assert!(matches!(stat, Interrupt::None));
+    }
+
+    #[test]
+    fn lcd_on_off() {
+        let mut ram = ram();
+        ram.write(0xff40, 0x91);
+        // line 0 starts without oam scan and is a clock shorter
+        Video::step(&mut ram, 1);
+        assert_eq!(ram.read(0xff41) & 3, 0);
+        run(&mut ram, 112);
+        assert_eq!(ram.read(0xff44), 1);
+        Video::step(&mut ram, 114);
+        assert_eq!(ram.read(0xff41) & 3, 2);
+
+        // the frame after enabling is not shown
+        assert!(ram.video.lcd_off());
+        run(&mut ram, FRAME);
+        assert!(!ram.video.lcd_off());
+
+        ram.write(0xff40, 0x11);
+        assert!(ram.video.lcd_off());
+        assert_eq!(ram.read(0xff44), 0);
+        assert_eq!(ram.read(0xff41) & 3, 0);
+        assert!(ram.video.back_buffer.iter().all(|p| *p == 0));
+        Video::step(&mut ram, 1);
+        assert_eq!(ram.read(0xff44), 0);
+    }
+
+    #[test]
+    fn sprite_priority() {
+        for renderer in [Renderer::Scanline, Renderer::Fifo].iter() {
+            let mut ram = ram();
+            ram.write(0xff40, 0x00);
+            for row in 0..8 {
+                // tile 1 in color 3, tile 2 has its left half in color 1
+                ram.write(0x8010 + row * 2, 0xff);
+                ram.write(0x8011 + row * 2, 0xff);
+                ram.write(0x8020 + row * 2, 0xf0);
+            }
+            let sprites = [
+                // the smaller x is in front, its transparent half not
+                (24, 2),
+                (20, 1),
+                (30, 2),
+                (32, 1),
+                // same x, the smaller oam index is in front
+                (50, 1),
+                (50, 2),
+                // outside the screen but still counted, the 11th is dropped
+                (0, 1),
+                (0, 1),
+                (168, 1),
+                (168, 1),
+                (100, 1),
+            ];
+            for (i, (x, tile)) in sprites.iter().enumerate() {
+                let a = 0xfe00 + i as u16 * 4;
+                ram.write(a, 16);
+                ram.write(a + 1, *x);
+                ram.write(a + 2, *tile);
+            }
+            ram.write(0xff47, 0xe4);
+            ram.write(0xff48, 0xe4);
+            ram.video.renderer = *renderer;
+            ram.write(0xff40, 0x93);
+            run(&mut ram, FRAME - 1);
+            let line = &ram.video.back_buffer[0..160];
+            let expect = |from: usize, to: usize, shade: u8| {
+                for (x, pixel) in line.iter().enumerate().take(to).skip(from) {
+                    assert_eq!(*pixel, shade, "{:?} pixel {}", renderer, x);
+                }
+            };
+            // shade and source, see palette.rs
+            let (white, obp0_1, obp0_3) = (0, 4 | 1, 4 | 3);
+            expect(0, 12, white);
+            expect(12, 20, obp0_3);
+            expect(20, 22, white);
+            expect(22, 24, obp0_1);
+            expect(24, 26, obp0_1);
+            expect(26, 32, obp0_3);
+            expect(42, 50, obp0_3);
+            expect(50, 160, white);
+        }
+    }
+
+    #[test]
+    fn window_quirks() {
+        for renderer in [Renderer::Scanline, Renderer::Fifo].iter() {
+            let mut ram = ram();
+            ram.write(0xff40, 0x00);
+            // the window shows tile 1, its row r in color r % 4, shown in the
+            // same shade
+            for row in 0..8 {
+                ram.write(0x8010 + row * 2, if row & 1 != 0 { 0xff } else { 0x00 });
+                ram.write(0x8011 + row * 2, if row & 2 != 0 { 0xff } else { 0x00 });
+            }
+            for a in 0x9c00..0xa000 {
+                ram.write(a, 1);
+            }
+            ram.write(0xff47, 0xe4);
+            ram.write(0xff4a, 10);
+            ram.write(0xff4b, 7);
+            ram.video.renderer = *renderer;
+            ram.write(0xff40, 0xf1);
+            let mut line = 0;
+            for clock in 0..FRAME - 1 {
+                Video::step(&mut ram, clock);
+                if ram.video.read_line() == line {
+                    continue;
+                }
+                line = ram.video.read_line();
+                match line {
+                    // WY is latched once it matched
+                    12 => ram.write(0xff4a, 200),
+                    // the window line counter stops while it is disabled
+                    20 => ram.write(0xff40, 0xd1),
+                    30 => ram.write(0xff40, 0xf1),
+                    // WX=166 shows one pixel then all the next line
+                    41 => ram.write(0xff4b, 166),
+                    42 => ram.write(0xff4b, 255),
+                    _ => {}
+                }
+            }
+            let screen = &ram.video.back_buffer;
+            let line = |l: usize| &screen[l * 160..(l + 1) * 160];
+            let check = |l: usize, from: usize, to: usize, shade: u8| {
+                for (x, pixel) in line(l).iter().enumerate().take(to).skip(from) {
+                    assert_eq!(*pixel, shade, "{:?} line {} pixel {}", renderer, l, x);
+                }
+            };
+            check(11, 0, 160, 1);
+            check(13, 0, 160, 3);
+            check(25, 0, 160, 0);
+            // window row 10
+            check(30, 0, 160, 2);
+            check(41, 0, 159, 0);
+            check(41, 159, 160, 1);
+            check(42, 0, 160, 2);
+            check(43, 0, 160, 0);
         }
-        self.vram[a as usize]
     }
 }
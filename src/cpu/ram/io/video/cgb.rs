@@ -0,0 +1,222 @@
+// Game Boy Color additions to the ppu: the second vram bank selected by VBK,
+// holding the attributes of the background maps, and the 8 background and 8
+// sprite palettes of 4 RGB555 colors written through BCPS/BCPD and OCPS/OCPD.
+
+use super::Video;
+
+// background map attribute bits, in vram bank 1 at the address of the tile
+pub const ATTR_PRIORITY: u8 = 0x80;
+pub const ATTR_Y_FLIP: u8 = 0x40;
+pub const ATTR_X_FLIP: u8 = 0x20;
+pub const ATTR_BANK: u8 = 0x08;
+
+// tiles of vram bank 1 follow those of bank 0 in the decoded tiles
+pub const BANK_1_TILES: usize = 0x180;
+
+pub struct CgbPalettes {
+    data: [u8; 64],
+    // bit 7 increments the index after each data write
+    index: u8,
+}
+
+impl CgbPalettes {
+    pub fn origin() -> CgbPalettes {
+        CgbPalettes {
+            data: [0; 64],
+            index: 0,
+        }
+    }
+
+    pub fn read_index(&self) -> u8 {
+        self.index | 0x40
+    }
+
+    pub fn write_index(&mut self, v: u8) {
+        self.index = v & 0xbf;
+    }
+
+    pub fn read_data(&self) -> u8 {
+        self.data[(self.index & 0x3f) as usize]
+    }
+
+    pub fn write_data(&mut self, v: u8) {
+        self.data[(self.index & 0x3f) as usize] = v;
+        self.increment();
+    }
+
+    fn increment(&mut self) {
+        if self.index & 0x80 != 0 {
+            self.index = 0x80 | (self.index + 1) & 0x3f;
+        }
+    }
+
+    // the RGB555 color `color` of `palette`
+    pub fn color(&self, palette: u8, color: u8) -> u16 {
+        let i = (palette & 7) as usize * 8 + color as usize * 2;
+        self.data[i] as u16 | (self.data[i + 1] as u16) << 8
+    }
+
+    pub fn fill(&mut self, v: u8) {
+        self.data = [v; 64];
+    }
+}
+
+pub fn rgb555_to_rgb(c: u16) -> [u8; 3] {
+    let scale = |v: u16| ((v & 0x1f) << 3 | (v & 0x1f) >> 2) as u8;
+    [scale(c), scale(c >> 5), scale(c >> 10)]
+}
+
+impl Video {
+    pub fn read_vram_bank(&self) -> u8 {
+        0xfe | (self.vram_bank / 0x2000) as u8
+    }
+
+    pub fn write_vram_bank(&mut self, v: u8) {
+        self.vram_bank = (v & 1) as usize * 0x2000;
+    }
+
+    // the palettes can not be accessed during mode 3
+    pub fn read_bg_palette_data(&self) -> u8 {
+        if self.vram_blocked() {
+            0xff
+        } else {
+            self.bg_palettes.read_data()
+        }
+    }
+
+    // a blocked write still increments the index
+    pub fn write_bg_palette_data(&mut self, v: u8) {
+        if self.vram_blocked() {
+            self.bg_palettes.increment();
+        } else {
+            self.bg_palettes.write_data(v);
+        }
+    }
+
+    pub fn read_obj_palette_data(&self) -> u8 {
+        if self.vram_blocked() {
+            0xff
+        } else {
+            self.obj_palettes.read_data()
+        }
+    }
+
+    pub fn write_obj_palette_data(&mut self, v: u8) {
+        if self.vram_blocked() {
+            self.obj_palettes.increment();
+        } else {
+            self.obj_palettes.write_data(v);
+        }
+    }
+
+    // OPRI, bit 0 set gives the DMG priority by x to the sprites
+    pub fn read_object_priority(&self) -> u8 {
+        0xfe | self.object_priority_x as u8
+    }
+
+    pub fn write_object_priority(&mut self, v: u8) {
+        self.object_priority_x = v & 1 != 0;
+    }
+
+    pub fn cgb_frame(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.cgb_back_buffer.len() * 4);
+        for c in self.cgb_back_buffer.iter() {
+            out.extend_from_slice(&rgb555_to_rgb(*c));
+            out.push(255);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::*;
+    use super::super::Renderer;
+    use cpu::Bus;
+
+    // one CGB frame with a flipped bank 1 tile, a tile over the sprites and two
+    // sprites ordered by oam index
+    fn cgb_frame(renderer: Renderer, lcdc: u8) -> Vec<u8> {
+        let mut ram = ram();
+        ram.set_cgb(true);
+        ram.write(0xff40, 0x00);
+        // white red, green blue
+        ram.write(0xff68, 0x80);
+        for c in [0x7fff, 0x001f, 0, 0, 0x03e0, 0x7c00, 0, 0].iter() {
+            ram.write(0xff69, *c as u8);
+            ram.write(0xff69, (*c >> 8) as u8);
+        }
+        assert_eq!(ram.read(0xff68), 0xd0, "BCPS incremented");
+        ram.write(0xff68, 0x02);
+        assert_eq!(ram.read(0xff69), 0x1f, "BCPD");
+        // magenta, yellow
+        ram.write(0xff6a, 0x82);
+        ram.write(0xff6b, 0x1f);
+        ram.write(0xff6b, 0x7c);
+        ram.write(0xff6a, 0x8a);
+        ram.write(0xff6b, 0xff);
+        ram.write(0xff6b, 0x03);
+        // tile 1 in color 1 in bank 0, only its left half in bank 1
+        for row in 0..8 {
+            ram.write(0x8010 + row * 2, 0xff);
+        }
+        ram.write(0x9800, 1);
+        ram.write(0x9802, 1);
+        ram.write(0xff4f, 0x01);
+        assert_eq!(ram.read(0xff4f), 0xff);
+        for row in 0..8 {
+            ram.write(0x8010 + row * 2, 0xf0);
+        }
+        // bank 1 x flipped with palette 1, then palette 0 over the sprites
+        ram.write(0x9800, 0x29);
+        ram.write(0x9802, 0x80);
+        ram.write(0xff4f, 0x00);
+        for (i, (x, attr)) in [(8 + 34, 1), (8 + 32, 0), (8 + 16, 0)].iter().enumerate() {
+            let a = 0xfe00 + i as u16 * 4;
+            ram.write(a, 16);
+            ram.write(a + 1, *x);
+            ram.write(a + 2, 1);
+            ram.write(a + 3, *attr);
+        }
+        ram.video.renderer = renderer;
+        ram.write(0xff40, lcdc);
+        run(&mut ram, FRAME - 1);
+        ram.video.frame()
+    }
+
+    #[test]
+    fn cgb_video() {
+        let white = [255, 255, 255];
+        let red = [255, 0, 0];
+        let green = [0, 255, 0];
+        let blue = [0, 0, 255];
+        let magenta = [255, 0, 255];
+        let yellow = [255, 255, 0];
+        for renderer in [Renderer::Scanline, Renderer::Fifo].iter() {
+            for lcdc in [0x93, 0x92].iter() {
+                let frame = cgb_frame(*renderer, *lcdc);
+                let expect = |from: usize, to: usize, color: [u8; 3]| {
+                    for x in from..to {
+                        assert_eq!(
+                            frame[x * 4..x * 4 + 3],
+                            color,
+                            "{:?} lcdc {:02x} pixel {}",
+                            renderer,
+                            lcdc,
+                            x
+                        );
+                    }
+                };
+                expect(0, 4, green);
+                expect(4, 8, blue);
+                expect(8, 16, white);
+                // without LCDC bit 0 the sprites are above everything
+                expect(16, 24, if *lcdc == 0x93 { red } else { magenta });
+                expect(24, 32, white);
+                expect(32, 34, magenta);
+                expect(34, 42, yellow);
+                expect(42, 160, white);
+            }
+        }
+    }
+}
@@ -0,0 +1,280 @@
+// DMG cartridges on the CGB: the boot rom gives them colors by loading one of
+// its compatibility palettes in place of the gray shades, chosen from the
+// title of Nintendo games or with a direction and a button held during the
+// logo. In DMG mode the shades still go through BGP, OBP0 and OBP1, so these
+// are regular palettes of 4 colors for the background and each sprite palette.
+
+use super::palette::Palette;
+
+// the colors of the boot rom, 4 for each palette
+#[rustfmt::skip]
+const COLORS: [u16; 30 * 4] = [
+    0x7fff, 0x32bf, 0x00d0, 0x0000,
+    0x639f, 0x4279, 0x15b0, 0x04cb,
+    0x7fff, 0x6e31, 0x454a, 0x0000,
+    0x7fff, 0x1bef, 0x0200, 0x0000,
+    0x7fff, 0x421f, 0x1cf2, 0x0000,
+    0x7fff, 0x5294, 0x294a, 0x0000,
+    0x7fff, 0x03ff, 0x012f, 0x0000,
+    0x7fff, 0x03ef, 0x01d6, 0x0000,
+    0x7fff, 0x42b5, 0x3dc8, 0x0000,
+    0x7e74, 0x03ff, 0x0180, 0x0000,
+    0x67ff, 0x77ac, 0x1a13, 0x2d6b,
+    0x7ed6, 0x4bff, 0x2175, 0x0000,
+    0x53ff, 0x4a5f, 0x7e52, 0x0000,
+    0x4fff, 0x7ed2, 0x3a4c, 0x1ce0,
+    0x03ed, 0x7fff, 0x255f, 0x0000,
+    0x036a, 0x021f, 0x03ff, 0x7fff,
+    0x7fff, 0x01df, 0x0112, 0x0000,
+    0x231f, 0x035f, 0x00f2, 0x0009,
+    0x7fff, 0x03ea, 0x011f, 0x0000,
+    0x299f, 0x001a, 0x000c, 0x0000,
+    0x7fff, 0x027f, 0x001f, 0x0000,
+    0x7fff, 0x03e0, 0x0206, 0x0120,
+    0x7fff, 0x7eeb, 0x001f, 0x7c00,
+    0x7fff, 0x3fff, 0x7e00, 0x001f,
+    0x7fff, 0x03ff, 0x001f, 0x0000,
+    0x03ff, 0x001f, 0x000c, 0x0000,
+    0x7fff, 0x033f, 0x0193, 0x0000,
+    0x0000, 0x4200, 0x037f, 0x7fff,
+    0x7fff, 0x7e8c, 0x7c00, 0x0000,
+    0x7fff, 0x1bef, 0x6180, 0x0000,
+];
+
+// the first color in COLORS of OBP0, OBP1 and BGP, most start a palette but
+// a few begin in the middle of one
+const fn p(obp0: usize, obp1: usize, bg: usize) -> [usize; 3] {
+    [obp0 * 4, obp1 * 4, bg * 4]
+}
+
+const COMBINATIONS: [[usize; 3]; 51] = [
+    p(4, 4, 29),
+    p(18, 18, 18),
+    p(20, 20, 20),
+    p(24, 24, 24),
+    p(9, 9, 9),
+    p(0, 0, 0),
+    p(27, 27, 27),
+    p(5, 5, 5),
+    p(12, 12, 12),
+    p(26, 26, 26),
+    p(16, 8, 8),
+    p(4, 28, 28),
+    p(4, 2, 2),
+    p(3, 4, 4),
+    p(4, 29, 29),
+    p(28, 4, 28),
+    p(2, 17, 2),
+    p(16, 16, 8),
+    p(4, 4, 7),
+    p(4, 4, 18),
+    p(4, 4, 20),
+    p(19, 19, 9),
+    [4 * 4 - 1, 4 * 4 - 1, 11 * 4],
+    p(17, 17, 2),
+    p(4, 4, 2),
+    p(4, 4, 3),
+    p(28, 28, 0),
+    p(3, 3, 0),
+    p(0, 0, 1),
+    p(18, 22, 18),
+    p(20, 22, 20),
+    p(24, 22, 24),
+    p(16, 22, 8),
+    p(17, 4, 13),
+    [28 * 4 - 1, 0, 14 * 4],
+    [28 * 4 - 1, 4 * 4, 15 * 4],
+    p(19, 22, 9),
+    p(16, 28, 10),
+    p(4, 23, 28),
+    p(17, 22, 2),
+    p(4, 0, 2),
+    p(4, 28, 3),
+    p(28, 3, 0),
+    p(3, 28, 4),
+    p(21, 28, 4),
+    p(3, 28, 0),
+    p(25, 3, 28),
+    p(0, 28, 8),
+    p(4, 3, 28),
+    p(28, 3, 6),
+    p(4, 28, 29),
+];
+
+// the sums of the titles known to the boot rom, from index 65 on the sums are
+// shared and the 4th letter of the title tells them apart
+#[rustfmt::skip]
+const TITLE_HASHES: [u8; 94] = [
+    0x00, 0x88, 0x16, 0x36, 0xd1, 0xdb, 0xf2, 0x3c, 0x8c, 0x92, 0x3d, 0x5c, 0x58, 0xc9, 0x3e, 0x70,
+    0x1d, 0x59, 0x69, 0x19, 0x35, 0xa8, 0x14, 0xaa, 0x75, 0x95, 0x99, 0x34, 0x6f, 0x15, 0xff, 0x97,
+    0x4b, 0x90, 0x17, 0x10, 0x39, 0xf7, 0xf6, 0xa2, 0x49, 0x4e, 0x43, 0x68, 0xe0, 0x8b, 0xf0, 0xce,
+    0x0c, 0x29, 0xe8, 0xb7, 0x86, 0x9a, 0x52, 0x01, 0x9d, 0x71, 0x9c, 0xbd, 0x5d, 0x6d, 0x67, 0x3f,
+    0x6b, 0xb3, 0x46, 0x28, 0xa5, 0xc6, 0xd3, 0x27, 0x61, 0x18, 0x66, 0x6a, 0xbf, 0x0d, 0xf4, 0xb3,
+    0x46, 0x28, 0xa5, 0xc6, 0xd3, 0x27, 0x61, 0x18, 0x66, 0x6a, 0xbf, 0x0d, 0xf4, 0xb3,
+];
+const FIRST_SHARED: usize = 65;
+const LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+// the combination of each title, 0x80 for the ones needing the tile map the
+// DMG boot rom leaves, which get the default palette
+#[rustfmt::skip]
+const TITLE_PALETTES: [u8; 94] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7 | 0x80, 37, 30, 44,
+    21, 32, 31, 20, 5, 33, 13, 14, 5, 29, 5, 18, 9, 3, 2, 26,
+    25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26 | 0x80, 42, 30, 41, 34, 34,
+    5, 42, 6, 5, 33, 25, 42, 42, 40, 2, 16, 25, 42, 42, 5, 0,
+    39, 36, 22, 25, 6, 32, 12, 36, 11, 39, 18, 39, 24, 31, 50, 17,
+    46, 6, 27, 0, 47, 41, 41, 0, 0, 19, 34, 23, 18, 29,
+];
+
+// each 5 bits component rounded to 8 bits, like the colors usually given
+// for these palettes
+fn rgb(c: u16) -> [u8; 3] {
+    let scale = |v: u16| (((v & 0x1f) as u32 * 255 + 15) / 31) as u8;
+    [scale(c), scale(c >> 5), scale(c >> 10)]
+}
+
+fn combination(index: usize) -> Palette {
+    let shades = |first: usize| {
+        let mut out = [[0; 3]; 4];
+        for (i, o) in out.iter_mut().enumerate() {
+            *o = rgb(COLORS[first + i]);
+        }
+        out
+    };
+    let [obp0, obp1, bg] = COMBINATIONS[index];
+    Palette {
+        bg: shades(bg),
+        obp0: shades(obp0),
+        obp1: shades(obp1),
+    }
+}
+
+// the palette picked by a direction alone or with A or B, `combo` is like
+// "up", "left+a" or "right+b"
+pub fn from_combo(combo: &str) -> Option<Palette> {
+    let index = match combo.to_lowercase().as_str() {
+        "up" => 5,
+        "up+a" => 43,
+        "up+b" => 28,
+        "left" => 48,
+        "left+a" => 40,
+        "left+b" => 7,
+        "down" => 8,
+        "down+a" => 3,
+        "down+b" => 49,
+        "right" => 1,
+        "right+a" => 0,
+        "right+b" => 6,
+        _ => return None,
+    };
+    Some(combination(index))
+}
+
+// the palette of a Nintendo title from Cartridge::compat_hash, None for the
+// ones the boot rom does not know
+pub fn from_title(hash: u8, letter: u8) -> Option<Palette> {
+    let index = TITLE_HASHES
+        .iter()
+        .enumerate()
+        .skip(1)
+        .find(|(i, h)| **h == hash && (*i < FIRST_SHARED || LETTERS[i - FIRST_SHARED] == letter))
+        .map(|(i, _)| TITLE_PALETTES[i])?;
+    if index & 0x80 != 0 {
+        return None;
+    }
+    Some(combination(index as usize))
+}
+
+// what the boot rom loads for a cartridge it does not know, the same as
+// right+a
+pub fn default() -> Palette {
+    combination(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::cartridge::Cartridge;
+
+    fn hex(colors: [u32; 4]) -> [[u8; 3]; 4] {
+        let mut out = [[0; 3]; 4];
+        for (o, c) in out.iter_mut().zip(colors.iter()) {
+            *o = [(c >> 16) as u8, (c >> 8) as u8, *c as u8];
+        }
+        out
+    }
+
+    // a Nintendo cartridge titled `title`
+    fn cartridge(title: &[u8]) -> Cartridge {
+        let mut cart = Cartridge::default();
+        cart.rom[0x134..0x134 + title.len()].copy_from_slice(title);
+        cart.rom[0x14b] = 0x01;
+        cart
+    }
+
+    fn title_palette(title: &[u8]) -> Option<Palette> {
+        let (hash, letter) = cartridge(title).compat_hash().unwrap();
+        from_title(hash, letter)
+    }
+
+    #[test]
+    fn compat_palettes() {
+        for direction in ["up", "down", "left", "right"].iter() {
+            for button in ["", "+a", "+b"].iter() {
+                assert!(from_combo(&format!("{}{}", direction, button)).is_some());
+            }
+        }
+        assert!(from_combo("up+start").is_none());
+
+        let right = from_combo("right").unwrap();
+        let green = hex([0xffffff, 0x52ff00, 0xff4200, 0x000000]);
+        assert_eq!((right.bg, right.obp0, right.obp1), (green, green, green));
+        let right_a = from_combo("right+a").unwrap();
+        assert_eq!(right_a.bg, hex([0xffffff, 0x7bff31, 0x0063c5, 0x000000]));
+        assert_eq!(right_a.obp0, hex([0xffffff, 0xff8484, 0x943a3a, 0x000000]));
+        assert_eq!(right_a.obp1, right_a.obp0);
+        assert_eq!(default().bg, right_a.bg);
+        let left_a = from_combo("left+a").unwrap();
+        assert_eq!(left_a.bg, hex([0xffffff, 0x8c8cde, 0x52528c, 0x000000]));
+        assert_eq!(left_a.obp1, hex([0xffffff, 0xffad63, 0x843100, 0x000000]));
+        let up_b = from_combo("up+b").unwrap();
+        assert_eq!(up_b.bg, hex([0xffe6c5, 0xce9c84, 0x846b29, 0x5a3108]));
+        let right_b = from_combo("right+b").unwrap();
+        assert_eq!(right_b.bg, hex([0x000000, 0x008484, 0xffde00, 0xffffff]));
+
+        let mut cart = Cartridge::default();
+        cart.rom[0x134..0x13a].copy_from_slice(b"TETRIS");
+        assert_eq!(cart.compat_hash(), None, "not Nintendo");
+        cart.rom[0x14b] = 0x01;
+        assert_eq!(cart.compat_hash(), Some((0xdb, b'R')));
+    }
+
+    #[test]
+    fn title_palettes() {
+        // Super Mario Land starts its sprite palettes with black
+        let sml = title_palette(b"SUPER MARIOLAND").unwrap();
+        assert_eq!(sml.bg, hex([0xb5b5ff, 0xffff94, 0xad5a42, 0x000000]));
+        assert_eq!(sml.obp0, hex([0x000000, 0xffffff, 0xff8484, 0x943a3a]));
+        let blue = title_palette(b"POKEMON BLUE").unwrap();
+        assert_eq!(blue.bg, hex([0xffffff, 0x63a5ff, 0x0000ff, 0x000000]));
+        assert_eq!(blue.obp0, hex([0xffffff, 0xff8484, 0x943a3a, 0x000000]));
+        let red = title_palette(b"POKEMON RED").unwrap();
+        assert_eq!(red.bg, hex([0xffffff, 0xff8484, 0x943a3a, 0x000000]));
+        assert_eq!(red.obp0, hex([0xffffff, 0x7bff31, 0x008400, 0x000000]));
+        let tetris = title_palette(b"TETRIS").unwrap();
+        assert_eq!(tetris.bg, hex([0xffffff, 0xffff00, 0xff0000, 0x000000]));
+
+        // titles sharing a sum, told apart by their 4th letter
+        let (hash, letter) = cartridge(b"SUPER MARIOLAND").compat_hash().unwrap();
+        assert_eq!((hash, letter), (0x46, b'E'));
+        assert!(
+            from_title(0x46, b'R').is_some(),
+            "the other title of the sum"
+        );
+        assert!(from_title(0x46, b'Z').is_none());
+        assert!(from_title(0x00, b'A').is_none(), "not a title sum");
+        // X needs the DMG tile map
+        assert!(from_title(0x58, b' ').is_none());
+    }
+}
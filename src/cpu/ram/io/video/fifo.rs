@@ -0,0 +1,466 @@
+// Pixel fifo renderer: during mode 3 the lcd takes one pixel per dot (4 per
+// clock) from the background fifo, mixed with the sprite fifo. The fetcher
+// refills the background fifo 8 pixels at a time and is restarted by the
+// window, sprites stop the output while they are fetched, so the length of
+// mode 3 depends on SCX, the window and the sprites of the line, and registers
+// written during mode 3 apply from the next pixels on.
+
+use super::{BgPixel, Pixel, Sprite, Video, TRANSPARENT};
+use std::collections::VecDeque;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Renderer {
+    // the whole line is drawn at the start of mode 3, which has a fixed length
+    Scanline,
+    Fifo,
+}
+
+// dots before the first pixel reaches the lcd (the first tile is fetched twice)
+const START_DELAY: u8 = 12;
+// dots the output waits for a sprite to be fetched
+const SPRITE_DELAY: u8 = 6;
+// more dots while the fetcher ends the background tile, at most for a sprite
+// at x 0
+const SPRITE_ALIGN: u8 = 5;
+// dots the fetcher needs for the tile number and both data bytes
+const FETCH_DOTS: u8 = 6;
+
+pub struct Fifo {
+    pub active: bool,
+    bg: VecDeque<BgPixel>,
+    obj: VecDeque<Pixel>,
+    // next pixel of the lcd
+    x: u8,
+    // pixels thrown away before the first one shown (SCX fine scroll, window
+    // left of the screen)
+    discard: u8,
+    // tile column and progress of the background fetcher
+    fetch_x: u8,
+    fetch_dot: u8,
+    // the fetcher is on the window tiles
+    window: bool,
+    // the window was shown on this line
+    window_drawn: bool,
+    // x of the last window trigger, it only happens once per x
+    trigger_x: Option<u8>,
+    // the window fills this line after starting on the last pixel of the previous one
+    wrap: bool,
+    stall: u8,
+    // the sprites of the line not fetched yet
+    sprites: Vec<Sprite>,
+    // the background tile of the last sprite fetched, it waits for the
+    // fetcher only once
+    sprite_tile: Option<u8>,
+}
+
+impl Fifo {
+    pub fn origin() -> Fifo {
+        Fifo {
+            active: false,
+            bg: VecDeque::with_capacity(16),
+            obj: VecDeque::with_capacity(8),
+            x: 0,
+            discard: 0,
+            fetch_x: 0,
+            fetch_dot: 0,
+            window: false,
+            window_drawn: false,
+            trigger_x: None,
+            wrap: false,
+            stall: 0,
+            sprites: Vec::with_capacity(10),
+            sprite_tile: None,
+        }
+    }
+}
+
+impl Video {
+    pub fn fifo_start_line(&mut self) {
+        self.fifo.sprites = self.oam_scan();
+        let fifo = &mut self.fifo;
+        fifo.active = true;
+        fifo.bg.clear();
+        fifo.obj.clear();
+        fifo.x = 0;
+        fifo.discard = self.scroll_x % 8;
+        fifo.fetch_x = 0;
+        fifo.fetch_dot = 0;
+        fifo.window = false;
+        fifo.window_drawn = false;
+        fifo.trigger_x = None;
+        fifo.wrap = std::mem::replace(&mut self.window_wrap, false);
+        fifo.stall = START_DELAY;
+        fifo.sprite_tile = None;
+    }
+
+    // runs the fifo for `dots` dots, returns true once the line is complete
+    pub fn fifo_dots(&mut self, dots: u8) -> bool {
+        for _ in 0..dots {
+            self.fifo_dot();
+            if !self.fifo.active {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn fifo_dot(&mut self) {
+        self.fetch();
+        if self.fifo.stall > 0 {
+            self.fifo.stall -= 1;
+            return;
+        }
+        if self.fifo.bg.is_empty() {
+            return;
+        }
+        let x = self.fifo.x;
+        if self.window_trigger(x) {
+            // the fetcher starts over on the window tiles, when WX matches again
+            // on the same line the window goes on from the tile it was at
+            let wx = self.window_scroll_x;
+            let fifo = &mut self.fifo;
+            if !fifo.window_drawn {
+                fifo.fetch_x = 0;
+            }
+            fifo.window = true;
+            fifo.window_drawn = true;
+            fifo.trigger_x = Some(x);
+            fifo.bg.clear();
+            fifo.fetch_dot = 0;
+            fifo.discard = match wx {
+                _ if fifo.wrap && x == 0 => 0,
+                // moves with the fine scroll
+                0 => 7 + self.scroll_x % 8,
+                1..=6 => 7 - wx,
+                _ => 0,
+            };
+            self.window_wrap = wx == 166;
+            return;
+        }
+        if self.fifo.discard == 0 && self.enable_sprites {
+            // the sprites left of the screen all start at the first pixel, the
+            // smaller x first
+            let found = self
+                .fifo
+                .sprites
+                .iter()
+                .enumerate()
+                .filter(|(_, s)| s.x == x + 8 || (x == 0 && s.x < 8))
+                .min_by_key(|(i, s)| (s.x, *i))
+                .map(|(i, _)| i);
+            if let Some(i) = found {
+                let s = self.fifo.sprites.remove(i);
+                self.fetch_sprite(&s);
+                // counting this dot
+                self.fifo.stall = SPRITE_DELAY - 1 + self.sprite_align(&s);
+                return;
+            }
+        }
+
+        let b = self
+            .fifo
+            .bg
+            .pop_front()
+            .unwrap_or(BgPixel { color: 0, attr: 0 });
+        let s = self.fifo.obj.pop_front().unwrap_or(TRANSPARENT);
+        if self.fifo.discard > 0 {
+            self.fifo.discard -= 1;
+            return;
+        }
+        self.put_pixel(x as usize, b, s);
+
+        self.fifo.x += 1;
+        if self.fifo.x == 160 {
+            self.fifo.active = false;
+            if self.fifo.window_drawn {
+                self.window_line += 1;
+            }
+        }
+    }
+
+    // WX is compared with every pixel once WY matched in the frame, WX under 7
+    // matches on the first one, on the DMG LCDC bit 0 also hides the window
+    fn window_trigger(&self, x: u8) -> bool {
+        if !self.enable_window
+            || (!self.enable_background && !self.cgb)
+            || !self.window_y_hit
+            || self.fifo.trigger_x == Some(x)
+        {
+            return false;
+        }
+        let wx = self.window_scroll_x;
+        (x == 0 && (self.fifo.wrap || wx < 7)) || x as u16 + 7 == wx as u16
+    }
+
+    // the dots left to the fetcher in the background or window tile under the
+    // first pixel of the sprite, minus 2
+    fn sprite_align(&mut self, s: &Sprite) -> u8 {
+        if s.x == 0 {
+            return SPRITE_ALIGN;
+        }
+        let origin = if self.fifo.window {
+            self.window_scroll_x.wrapping_sub(7)
+        } else {
+            0u8.wrapping_sub(self.scroll_x % 8)
+        };
+        let pixel = s.x.wrapping_sub(8).wrapping_sub(origin);
+        let tile = pixel / 8;
+        if self.fifo.sprite_tile.replace(tile) == Some(tile) {
+            return 0;
+        }
+        SPRITE_ALIGN.saturating_sub(pixel % 8)
+    }
+
+    // pushes the next 8 background or window pixels once the fifo is empty
+    fn fetch(&mut self) {
+        if self.fifo.fetch_dot < FETCH_DOTS {
+            self.fifo.fetch_dot += 1;
+            return;
+        }
+        if !self.fifo.bg.is_empty() {
+            return;
+        }
+        // back to the background when the window is disabled during the line
+        if self.fifo.window && !self.enable_window {
+            self.fifo.window = false;
+        }
+        let (tile_map, column, row) = if self.fifo.window {
+            let map = if self.window_tile_map { 0x1C00 } else { 0x1800 };
+            (map, self.fifo.fetch_x, self.window_line)
+        } else {
+            let map = if self.background_tile_map {
+                0x1C00
+            } else {
+                0x1800
+            };
+            let column = (self.scroll_x / 8).wrapping_add(self.fifo.fetch_x) % 32;
+            (map, column, self.line.wrapping_add(self.scroll_y))
+        };
+        let offset = tile_map + row as usize / 8 * 32 + column as usize;
+        let tile = self.vram[offset];
+        let attr = self.map_attr(offset);
+        for i in 0..8 {
+            let color = if self.enable_background || self.cgb {
+                self.bg_tile_pixel(tile, attr, row % 8, i)
+            } else {
+                0
+            };
+            self.fifo.bg.push_back(BgPixel { color, attr });
+        }
+        self.fifo.fetch_x = self.fifo.fetch_x.wrapping_add(1);
+        self.fifo.fetch_dot = 0;
+    }
+
+    // mixes the sprite in the sprite fifo, pixels of sprites already there win,
+    // on the CGB unless the new sprite comes first in oam
+    fn fetch_sprite(&mut self, s: &Sprite) {
+        let by_index = !self.dmg_sprite_priority();
+        let row = self.sprite_row(s);
+        let skip = 8u8.saturating_sub(s.x) as usize;
+        for (i, color) in row.iter().enumerate().skip(skip) {
+            let i = i - skip;
+            while self.fifo.obj.len() <= i {
+                self.fifo.obj.push_back(TRANSPARENT);
+            }
+            let old = self.fifo.obj[i];
+            if old.color == 0 || (by_index && *color != 0 && s.index < old.index) {
+                self.fifo.obj[i] = Pixel {
+                    behind_bg: s.behind_bg,
+                    palette: s.palette,
+                    color: *color,
+                    cgb_palette: s.attr & 7,
+                    index: s.index,
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::*;
+    use super::*;
+    use cpu::ram::Ram;
+    use cpu::Bus;
+
+    // a frame with fine scroll, window and sprites, drawn by `renderer`,
+    // returns it with the number of clocks of mode 3 on the first line
+    fn render(renderer: Renderer) -> (Vec<u8>, usize) {
+        let mut ram = ram();
+        ram.write(0xff40, 0x00);
+        for a in 0x8000..0xa000u16 {
+            let i = a as usize;
+            let v = if a < 0x9800 { i * 7 + (i >> 3) } else { i * 13 };
+            ram.write(a, v as u8);
+        }
+        let sprites = [
+            (26, 30, 3, 0x00),
+            (28, 34, 5, 0x80),
+            (56, 100, 7, 0x30),
+            (76, 4, 9, 0x40),
+            (90, 152, 11, 0x00),
+        ];
+        for (i, (y, x, tile, attr)) in sprites.iter().enumerate() {
+            let a = 0xfe00 + i as u16 * 4;
+            ram.write(a, *y);
+            ram.write(a + 1, *x);
+            ram.write(a + 2, *tile);
+            ram.write(a + 3, *attr);
+        }
+        for (a, v) in [
+            (0xff47, 0xe4),
+            (0xff48, 0xd2),
+            (0xff49, 0x1b),
+            (0xff42, 0x05),
+            (0xff43, 0x03),
+            (0xff4a, 50),
+            (0xff4b, 80),
+        ]
+        .iter()
+        {
+            ram.write(*a, *v);
+        }
+        ram.video.renderer = renderer;
+        ram.write(0xff40, 0xf3);
+        let mut mode_3 = 0;
+        for clock in 0..FRAME {
+            Video::step(&mut ram, clock);
+            if ram.video.read_line() == 0 && ram.read(0xff41) & 3 == 3 {
+                mode_3 += 1;
+            }
+        }
+        (ram.video.back_buffer.to_vec(), mode_3)
+    }
+
+    #[test]
+    fn fifo_renderer() {
+        let (scanline, scanline_mode_3) = render(Renderer::Scanline);
+        let (fifo, fifo_mode_3) = render(Renderer::Fifo);
+        for (i, (s, f)) in scanline.iter().zip(fifo.iter()).enumerate() {
+            assert_eq!(s, f, "pixel {} of line {}", i % 160, i / 160);
+        }
+        assert_eq!(scanline_mode_3, 43);
+        // 172 dots and the 3 pixels of fine scroll dropped
+        assert_eq!(fifo_mode_3, 44);
+    }
+
+    // clocks of mode 3 on line 1 after `setup`, with the fifo renderer, blank
+    // tiles and sprites enabled
+    fn mode_3_length(setup: impl Fn(&mut Ram)) -> usize {
+        let mut ram = ram();
+        ram.write(0xff40, 0x02);
+        setup(&mut ram);
+        let lcdc = ram.read(0xff40);
+        ram.write(0xff40, lcdc | 0x81);
+        let mut mode_3 = 0;
+        for clock in 0..3 * 114 {
+            Video::step(&mut ram, clock);
+            if ram.video.read_line() == 1 && ram.read(0xff41) & 3 == 3 {
+                mode_3 += 1;
+            }
+        }
+        mode_3
+    }
+
+    // sprites on line 1 at each x
+    fn line_1_sprites(ram: &mut Ram, xs: &[u8]) {
+        for (i, x) in xs.iter().enumerate() {
+            ram.write(0xfe00 + i as u16 * 4, 16 - 1);
+            ram.write(0xfe01 + i as u16 * 4, *x);
+        }
+    }
+
+    #[test]
+    fn fifo_mode_3_length() {
+        // 172 dots, 4 a clock
+        assert_eq!(mode_3_length(|_| {}), 43);
+        assert_eq!(
+            mode_3_length(|ram| ram.write(0xff43, 3)),
+            44,
+            "SCX fine scroll"
+        );
+        // 6 dots a sprite, and up to 5 more while the background tile is fetched
+        let sprites = |xs: &'static [u8], scx: u8| {
+            mode_3_length(move |ram| {
+                line_1_sprites(ram, xs);
+                ram.write(0xff43, scx);
+            })
+        };
+        assert_eq!(sprites(&[13], 0), 45, "172+6");
+        assert_eq!(sprites(&[8], 0), 46, "172+11");
+        assert_eq!(sprites(&[8, 9], 0), 48, "172+11+6, one tile");
+        assert_eq!(sprites(&[8, 16], 0), 49, "172+11+11, two tiles");
+        assert_eq!(sprites(&[13], 5), 47, "175+11, the tile moves with SCX");
+        // at x 0 always 11 dots
+        assert_eq!(sprites(&[0], 0), 46, "172+11");
+        assert_eq!(sprites(&[0], 3), 47, "175+11");
+        assert_eq!(sprites(&[0; 10], 0), 71, "172+110");
+        // sprites are not fetched when disabled
+        let hidden = mode_3_length(|ram| {
+            line_1_sprites(ram, &[0; 10]);
+            ram.write(0xff40, 0x01);
+        });
+        assert_eq!(hidden, 43);
+        // the window restarts the fetcher
+        let window = |wx: u8| {
+            mode_3_length(move |ram| {
+                ram.write(0xff4a, 0);
+                ram.write(0xff4b, wx);
+                ram.write(0xff40, 0x20);
+            })
+        };
+        assert_eq!(window(87), 45, "172+6");
+        assert_eq!(window(170), 43, "never reached");
+        let both = mode_3_length(|ram| {
+            ram.write(0xff4b, 87);
+            ram.write(0xff40, 0x22);
+            line_1_sprites(ram, &[8]);
+        });
+        assert_eq!(both, 48, "172+6+11");
+    }
+
+    // line 1 with `write` done `clocks` clocks into its mode 3, the map shows
+    // tile 0 on the first 20 columns and tile 1 on the others
+    fn mid_line_write(clocks: u32, write: (u16, u8)) -> Vec<u8> {
+        let mut ram = ram();
+        ram.write(0xff40, 0x00);
+        for row in 0..8 {
+            // tile 0 color 1, tile 1 color 2
+            ram.write(0x8000 + row * 2, 0xff);
+            ram.write(0x8011 + row * 2, 0xff);
+        }
+        for column in 20..32 {
+            ram.write(0x9800 + column, 1);
+        }
+        ram.write(0xff47, 0xe4);
+        ram.write(0xff40, 0x91);
+        run_until(&mut ram, |ram| {
+            ram.video.read_line() == 1 && ram.read(0xff41) & 3 == 3
+        });
+        run(&mut ram, clocks);
+        ram.write(write.0, write.1);
+        run_until(&mut ram, |ram| ram.video.read_line() != 1);
+        ram.video.back_buffer[160..320].to_vec()
+    }
+
+    #[test]
+    fn fifo_mid_line_writes() {
+        // SCX moves the tiles fetched after the write, 16 columns to the right
+        // they reach tile 1 until they wrap to column 0 at pixel 128
+        let line = mid_line_write(20, (0xff43, 128));
+        let change = line.iter().position(|c| *c == 2).unwrap();
+        assert!(change > 40 && change < 90, "{}", change);
+        assert!(line[..change].iter().all(|c| *c == 1));
+        assert!(line[change..128].iter().all(|c| *c == 2));
+        assert!(line[128..].iter().all(|c| *c == 1));
+        // BGP applies from the next pixel, color 1 is now shade 2
+        let line = mid_line_write(20, (0xff47, 0x1b));
+        let change = line.iter().position(|c| *c == 2).unwrap();
+        assert!(change > 40 && change < 90, "{}", change);
+        assert!(line[..change].iter().all(|c| *c == 1));
+        assert!(line[change..].iter().all(|c| *c == 2));
+        // later writes move the change to the right
+        let later = mid_line_write(30, (0xff47, 0x1b));
+        assert_eq!(later.iter().position(|c| *c == 2), Some(change + 40));
+    }
+}
@@ -0,0 +1,158 @@
+// back_buffer holds for every pixel its shade (0 lightest to 3 darkest, after
+// BGP, OBP0 or OBP1) in bits 0-1 and the palette it came from in bits 2-3, the
+// screen colors are chosen only when the frame is shown.
+
+use std::fs;
+
+pub const SOURCE_BG: u8 = 0;
+pub const SOURCE_OBP0: u8 = 1 << 2;
+pub const SOURCE_OBP1: u8 = 2 << 2;
+
+// the rgb color of the 4 shades for each source
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Palette {
+    pub bg: [[u8; 3]; 4],
+    pub obp0: [[u8; 3]; 4],
+    pub obp1: [[u8; 3]; 4],
+}
+
+impl Palette {
+    fn same(shades: [[u8; 3]; 4]) -> Palette {
+        Palette {
+            bg: shades,
+            obp0: shades,
+            obp1: shades,
+        }
+    }
+
+    pub fn gray() -> Palette {
+        Palette::same([[255, 255, 255], [140, 140, 140], [75, 75, 75], [0, 0, 0]])
+    }
+
+    // the green of the original DMG screen
+    pub fn green() -> Palette {
+        Palette::same([
+            [0x9b, 0xbc, 0x0f],
+            [0x8b, 0xac, 0x0f],
+            [0x30, 0x62, 0x30],
+            [0x0f, 0x38, 0x0f],
+        ])
+    }
+
+    pub fn pocket() -> Palette {
+        Palette::same([
+            [0xc4, 0xcf, 0xa1],
+            [0x8b, 0x95, 0x6d],
+            [0x4d, 0x53, 0x3c],
+            [0x1f, 0x1f, 0x1f],
+        ])
+    }
+
+    // the backlit Game Boy Light
+    pub fn light() -> Palette {
+        Palette::same([
+            [0x00, 0xb5, 0x81],
+            [0x00, 0x9a, 0x71],
+            [0x00, 0x69, 0x4a],
+            [0x00, 0x4f, 0x3b],
+        ])
+    }
+
+    pub fn from_name(name: &str) -> Option<Palette> {
+        match name.to_lowercase().as_str() {
+            "gray" | "grey" => Some(Palette::gray()),
+            "green" | "dmg" => Some(Palette::green()),
+            "pocket" | "mgb" => Some(Palette::pocket()),
+            "light" => Some(Palette::light()),
+            _ => None,
+        }
+    }
+
+    // a palette file has a line per source with its 4 colors, lightest first:
+    //     bg   = e0f8d0 88c070 346856 081820
+    //     obp0 = ...
+    // lines starting with # are comments, obp0 and obp1 default to bg
+    pub fn parse(text: &str) -> Result<Palette, String> {
+        let mut bg = None;
+        let mut obp0 = None;
+        let mut obp1 = None;
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, colors) = line
+                .split_once('=')
+                .ok_or_else(|| format!("line {}: expected name = colors", n + 1))?;
+            let mut shades = [[0; 3]; 4];
+            let list: Vec<&str> = colors.split_whitespace().collect();
+            if list.len() != 4 {
+                return Err(format!("line {}: expected 4 colors", n + 1));
+            }
+            for (shade, color) in shades.iter_mut().zip(list.iter()) {
+                let color = color.trim_start_matches('#');
+                let v = match u32::from_str_radix(color, 16) {
+                    Ok(v) if color.len() == 6 => v,
+                    _ => return Err(format!("line {}: bad color {}", n + 1, color)),
+                };
+                *shade = [(v >> 16) as u8, (v >> 8) as u8, v as u8];
+            }
+            match name.trim().to_lowercase().as_str() {
+                "bg" => bg = Some(shades),
+                "obp0" => obp0 = Some(shades),
+                "obp1" => obp1 = Some(shades),
+                other => return Err(format!("line {}: unknown palette {}", n + 1, other)),
+            }
+        }
+        let bg = bg.ok_or("no bg palette")?;
+        Ok(Palette {
+            bg,
+            obp0: obp0.unwrap_or(bg),
+            obp1: obp1.unwrap_or(bg),
+        })
+    }
+
+    pub fn load(path: &str) -> Result<Palette, String> {
+        let text =
+            fs::read_to_string(path).map_err(|e| format!("can not read palette {}: {}", path, e))?;
+        Palette::parse(&text).map_err(|e| format!("palette {}: {}", path, e))
+    }
+
+    pub fn color(&self, pixel: u8) -> [u8; 3] {
+        let shades = match pixel & 0x0c {
+            SOURCE_OBP0 => &self.obp0,
+            SOURCE_OBP1 => &self.obp1,
+            _ => &self.bg,
+        };
+        shades[(pixel & 3) as usize]
+    }
+
+    pub fn rgba(&self, buffer: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(buffer.len() * 4);
+        for p in buffer {
+            out.extend_from_slice(&self.color(*p));
+            out.push(255);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_palette_file() {
+        let p = Palette::parse(
+            "# custom\n\
+             bg = e0f8d0 88c070 346856 081820\n\
+             obp1 = #ff0000 #00ff00 #0000ff #000000\n",
+        )
+        .unwrap();
+        assert_eq!(p.bg[1], [0x88, 0xc0, 0x70]);
+        assert_eq!(p.obp0, p.bg);
+        assert_eq!(p.color(SOURCE_OBP1 | 2), [0, 0, 255]);
+        assert!(Palette::parse("bg = 000000 ffffff").is_err());
+        assert!(Palette::parse("obp0 = 000000 111111 222222 333333").is_err());
+    }
+}
@@ -1,7 +1,10 @@
+use cpu::*;
 use std::fmt;
 
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Registers {
     pub a: u8,
+    pub flags: Flags,
     pub b: u8,
     pub c: u8,
     pub d: u8,
@@ -10,14 +13,22 @@ pub struct Registers {
     pub l: u8,
     pub sp: u16,
     pub pc: u16,
+    // interrupt master enable, EI only sets it after the next instruction
+    pub ime: bool,
+    pub ime_delay: bool,
 }
 
 impl fmt::Display for Registers {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "A:{:02x} BC:{:02x}{:02x} DE:{:02x}{:02x} HL:{:02x}{:02x} SP:{:04x} PC:{:04x}",
-            self.a, self.b, self.c, self.d, self.e, self.h, self.l, self.sp, self.pc
+            "AF:{:04x} BC:{:04x} DE:{:04x} HL:{:04x} SP:{:04x} PC:{:04x}",
+            self.af(),
+            self.bc(),
+            self.de(),
+            self.hl(),
+            self.sp,
+            self.pc
         )
     }
 }
@@ -26,6 +37,7 @@ impl Registers {
     pub fn origin() -> Registers {
         Registers {
             a: 0,
+            flags: Flags::origin(),
             b: 0,
             c: 0,
             d: 0,
@@ -34,6 +46,40 @@ impl Registers {
             l: 0,
             sp: 0,
             pc: 0,
+            ime: false,
+            ime_delay: false,
         }
     }
+    pub fn af(&self) -> u16 {
+        u8tou16(self.flags.bits(), self.a)
+    }
+    pub fn bc(&self) -> u16 {
+        u8tou16(self.c, self.b)
+    }
+    pub fn de(&self) -> u16 {
+        u8tou16(self.e, self.d)
+    }
+    pub fn hl(&self) -> u16 {
+        u8tou16(self.l, self.h)
+    }
+    pub fn set_af(&mut self, v: u16) {
+        let (f, a) = u16tou8(v);
+        self.flags.set_bits(f);
+        self.a = a;
+    }
+    pub fn set_bc(&mut self, v: u16) {
+        let (c, b) = u16tou8(v);
+        self.c = c;
+        self.b = b;
+    }
+    pub fn set_de(&mut self, v: u16) {
+        let (e, d) = u16tou8(v);
+        self.e = e;
+        self.d = d;
+    }
+    pub fn set_hl(&mut self, v: u16) {
+        let (l, h) = u16tou8(v);
+        self.l = l;
+        self.h = h;
+    }
 }
@@ -9,6 +9,9 @@ extern crate libpulse_binding as pulse;
 extern crate libpulse_simple_binding as psimple;
 #[macro_use]
 extern crate derivative;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 #[macro_use]
 extern crate itertools;
 
@@ -28,6 +31,10 @@ use pulse::sample;
 use pulse::stream::Direction;
 use std::mem;
 mod cpu;
+mod record;
+mod screenshot;
+#[cfg(test)]
+mod test_roms;
 mod window;
 
 use cpu::*;
@@ -59,6 +66,12 @@ pub enum EmuCommand {
     PrintAudio4,
     PrintVideo,
     Save,
+    // writes the screen to a PNG
+    Screenshot,
+    // starts or stops recording the video and the audio
+    Record,
+    // starts or stops an animated GIF
+    Gif,
 }
 
 #[derive(Debug)]
@@ -70,7 +83,10 @@ pub enum ToEmu {
 }
 
 pub struct ToDisplay {
-    pub back_buffer: Box<[u8; 160 * 144]>,
+    // the screen in RGBA, 160x144 or 256x224 with the SGB border
+    pub frame: Vec<u8>,
+    pub width: usize,
+    pub height: usize,
     pub hram: Vec<u8>,
     pub window0: Option<Vec<u8>>,
     pub window1: Option<Vec<u8>>,
@@ -107,8 +123,11 @@ impl ToDisplay {
         let mut m = Vec::new();
         m.extend_from_slice(&ram.hram);
 
+        let (frame, width, height) = screenshot::screen(ram, None);
         ToDisplay {
-            back_buffer: Box::new(ram.video.back_buffer),
+            frame,
+            width,
+            height,
             hram: m,
             window0: w0,
             window1: w1,
@@ -118,21 +137,71 @@ impl ToDisplay {
     }
 }
 
+// clocks of a frame, 154 lines of 114
+const FRAME_CLOCKS: u32 = 154 * 114;
+// cpu cycles a speed switch takes
+const SPEED_SWITCH_CLOCKS: u32 = 2050;
+
 struct Gameboy {
     ram: cpu::ram::Ram,
-    reg: cpu::registers::Registers,
-    alu: cpu::alu::Alu,
+    cpu: cpu::Cpu,
+    // clock of the ppu and apu, the cpu one runs twice as fast in double speed
+    clock: u32,
+    capture: screenshot::Capture,
+    recorder: Option<record::Recorder>,
     running: bool,
     got_tick: bool,
+    breakpoint: bool,
 }
 impl Gameboy {
     fn origin(cart: cpu::cartridge::Cartridge) -> Gameboy {
         Gameboy {
             ram: cpu::ram::Ram::origin(cart),
-            reg: cpu::registers::Registers::origin(),
-            alu: cpu::alu::Alu::origin(),
+            cpu: cpu::Cpu::origin(),
+            clock: 0,
+            capture: screenshot::Capture::origin(),
+            recorder: None,
             got_tick: false,
             running: true,
+            breakpoint: false,
+        }
+    }
+
+    fn skip_boot(&mut self, model: cpu::boot::Model) {
+        cpu::boot::skip_boot(&mut self.cpu, &mut self.ram, model);
+    }
+
+    fn start_recording(&mut self, base: &std::path::Path) {
+        let (_, width, height) = screenshot::screen(&self.ram, None);
+        match record::Recorder::start(base, width, height) {
+            Ok(r) => {
+                println!("recording {}", base.display());
+                self.recorder = Some(r);
+            }
+            Err(e) => println!("{}", e),
+        }
+    }
+
+    fn stop_recording(&mut self) {
+        if let Some(r) = self.recorder.take() {
+            match r.finish() {
+                Ok(base) => println!("recorded {}", base.display()),
+                Err(e) => println!("{}", e),
+            }
+        }
+    }
+
+    // the frames are written at each emulated frame, with the lcd on or off
+    fn record_frame(&mut self) {
+        if let Err(e) = self.capture.gif_frame(&self.ram) {
+            println!("{}", e);
+        }
+        if let Some(r) = &mut self.recorder {
+            let (frame, _, _) = screenshot::screen(&self.ram, None);
+            if let Err(e) = r.frame(&frame) {
+                println!("{}", e);
+                self.recorder = None;
+            }
         }
     }
 
@@ -160,6 +229,22 @@ impl Gameboy {
             }
             ToEmu::Command(EmuCommand::PrintVideo) => println!("#### video\n{:?}", self.ram.video),
             ToEmu::Command(EmuCommand::Save) => self.ram.cart.save(),
+            ToEmu::Command(EmuCommand::Screenshot) => match self.capture.screenshot(&self.ram) {
+                Ok(path) => println!("screenshot {}", path.display()),
+                Err(e) => println!("{}", e),
+            },
+            ToEmu::Command(EmuCommand::Record) => {
+                if self.recorder.is_some() {
+                    self.stop_recording();
+                } else {
+                    self.start_recording(&record::Recorder::next_base());
+                }
+            }
+            ToEmu::Command(EmuCommand::Gif) => match self.capture.toggle_gif() {
+                Ok((path, true)) => println!("capturing {}", path.display()),
+                Ok((path, false)) => println!("captured {}", path.display()),
+                Err(e) => println!("{}", e),
+            },
             ToEmu::Command(EmuCommand::Quit) => self.running = false,
             _ => println!("{:?}", t),
         }
@@ -174,65 +259,88 @@ impl Gameboy {
         }
     }
 
+    // run every component for one clock, returns the vblank and audio events
+    fn step(&mut self) -> (cpu::ram::io::Interrupt, cpu::ram::io::Interrupt) {
+        // in double speed the cpu, timer, serial and oam DMA run two cycles
+        // for each one of the ppu and apu
+        let speed = if self.ram.double_speed { 2 } else { 1 };
+        let mut interrupted = false;
+        for _ in 0..speed {
+            interrupted = self.step_cpu() || interrupted;
+        }
+
+        self.clock = self.clock.wrapping_add(1);
+        let i_video = ram::io::Video::step(&mut self.ram, self.clock);
+        if let ram::io::Interrupt::VBlank = i_video.1 {
+            self.ram.sgb.vblank(&self.ram.video);
+        }
+        let i_hdma = ram::io::Hdma::step(&mut self.ram, self.clock);
+        let i_audio = self.ram.audio.step(self.clock);
+
+        interrupted = self.ram.interrupt.add_interrupt(&i_hdma) || interrupted;
+        interrupted = self.ram.interrupt.add_interrupt(&i_video.0) || interrupted;
+        interrupted = self.ram.interrupt.add_interrupt(&i_video.1) || interrupted;
+        if interrupted {
+            self.cpu.halted = false;
+        }
+        (i_video.1, i_audio)
+    }
+
+    // one cycle of the cpu and of the components on its clock, returns true
+    // if they raised an interrupt
+    fn step_cpu(&mut self) -> bool {
+        //print!("\n{:05x}{} ",self.cpu.cycles,self.cpu);
+        if self.ram.stall > 0 {
+            // stopped by vram DMA
+            self.ram.stall -= 1;
+            self.cpu.cycles += 1;
+        } else {
+            match self.cpu.step(&mut self.ram) {
+                CpuState::Stop => {
+                    if !self.ram.switch_speed() {
+                        panic!("Stop unimplemented, unsure what it should do");
+                    }
+                    // the cpu is paused while the clock changes
+                    self.ram.stall += SPEED_SWITCH_CLOCKS;
+                }
+                CpuState::Breakpoint => self.breakpoint = true,
+                _ => {}
+            }
+        }
+
+        //IO
+        let clock = self.cpu.cycles as u32;
+        let i_joypad = ram::io::Joypad::step(&mut self.ram, clock);
+        let i_serial = ram::io::Serial::step(&mut self.ram, clock);
+        let i_timer = ram::io::Timer::step(&mut self.ram, clock);
+        let i_dma = ram::io::Dma::step(&mut self.ram, clock);
+
+        let mut interrupted = false;
+        interrupted = self.ram.interrupt.add_interrupt(&i_joypad) || interrupted;
+        interrupted = self.ram.interrupt.add_interrupt(&i_serial) || interrupted;
+        interrupted = self.ram.interrupt.add_interrupt(&i_timer) || interrupted;
+        interrupted = self.ram.interrupt.add_interrupt(&i_dma) || interrupted;
+        interrupted
+    }
+
     fn main_loop(
         &mut self,
         mut rx: mpsc::Receiver<ToEmu>,
         mut tx: mpsc::Sender<ToDisplay>,
         mut s: Simple,
     ) {
-        let mut clock = 0 as u32;
-        let mut cpu_wait = 0;
         let mut buffer_index = 0;
         let mut buffer = [0; 512 * mem::size_of::<f64>()];
         let mut file = File::create("out.pcm").ok().unwrap();
-        let mut halted = false;
         //s.write(&buffer);
+        // clocks since the last blank frame sent while the lcd is off
+        let mut lcd_off_clock = 0;
 
         loop {
             if self.running == false {
                 break;
             }
-            clock = clock.wrapping_add(1);
-            if !halted {
-                if cpu_wait == 0 {
-                    //print!("\n{:05x}{}{} ",clock,self.alu,self.reg);
-                    if !halted {
-                        match instruct(&mut self.ram, &mut self.reg, &mut self.alu) {
-                            CpuState::None => {}
-                            CpuState::Wait(t) => cpu_wait = t,
-                            CpuState::Halt => {
-                                halted = true;
-                            }
-                            CpuState::Stop => {
-                                panic!("Stop unimplemented, unsure what it should do");
-                            }
-                        }
-                    }
-                    cpu::ram::io::InterruptManager::try_interrupt(&mut self.ram, &mut self.reg);
-                } else {
-                    cpu_wait -= 1;
-                }
-            }
-
-            //IO
-            let i_joypad = ram::io::Joypad::step(&mut self.ram, clock);
-            let i_serial = ram::io::Serial::step(&mut self.ram, clock);
-            let i_timer = ram::io::Timer::step(&mut self.ram, clock);
-            let i_dma = ram::io::Dma::step(&mut self.ram, clock);
-            let i_video = ram::io::Video::step(&mut self.ram, clock);
-            let i_audio = self.ram.audio.step(clock);
-            ram::io::InterruptManager::step(&mut self.ram, clock);
-
-            let mut interrupted = false;
-            interrupted = self.ram.interrupt.add_interrupt(&i_joypad) || interrupted;
-            interrupted = self.ram.interrupt.add_interrupt(&i_serial) || interrupted;
-            interrupted = self.ram.interrupt.add_interrupt(&i_timer) || interrupted;
-            interrupted = self.ram.interrupt.add_interrupt(&i_dma) || interrupted;
-            interrupted = self.ram.interrupt.add_interrupt(&i_video.0) || interrupted;
-            interrupted = self.ram.interrupt.add_interrupt(&i_video.1) || interrupted;
-            if interrupted {
-                halted = false;
-            }
+            let (i_video, i_audio) = self.step();
             match i_audio {
                 cpu::ram::io::Interrupt::AudioSample(l, r) => {
                     let size = mem::size_of::<f32>();
@@ -246,6 +354,12 @@ impl Gameboy {
                         .as_mut()
                         .write_f32::<LittleEndian>(r as f32)
                         .expect("failed to convert sound sample shape");
+                    if let Some(rec) = &mut self.recorder {
+                        if let Err(e) = rec.sample(l, r) {
+                            println!("{}", e);
+                            self.recorder = None;
+                        }
+                    }
                     buffer_index += 1;
                     if buffer_index * 2 * size >= buffer.len() {
                         s.write(&buffer).expect("Failed writing to sound buffer.");
@@ -258,11 +372,15 @@ impl Gameboy {
                 }
                 _ => {}
             };
-            match i_video.1 {
+            match i_video {
                 cpu::ram::io::Interrupt::VBlank => {
                     println!("got VBLANK");
                     tx.send(ToDisplay::collect(&mut self.ram)).unwrap();
                     self.ram.video.clear_update();
+                    if let Err(e) = self.capture.frame(&self.ram) {
+                        println!("{}", e);
+                    }
+                    self.record_frame();
                 }
                 cpu::ram::io::Interrupt::VBlankEnd => {
                     println!("got VBLANKEND");
@@ -270,10 +388,37 @@ impl Gameboy {
                 }
                 _ => {}
             };
+            // without vblanks the screen and the keys are refreshed once a frame
+            if self.ram.video.lcd_enabled() {
+                lcd_off_clock = 0;
+            } else {
+                lcd_off_clock += 1;
+                if lcd_off_clock == FRAME_CLOCKS {
+                    lcd_off_clock = 0;
+                    tx.send(ToDisplay::collect(&mut self.ram)).unwrap();
+                    self.record_frame();
+                    self.try_read_all(&mut rx);
+                }
+            }
         }
-        println!("stopped at pc = {:04x}", self.reg.pc);
+        self.stop_recording();
+        if let Some(path) = self.capture.stop_gif() {
+            println!("captured {}", path.display());
+        }
+        println!("stopped at pc = {:04x}", self.cpu.reg.pc);
     }
 }
+// a bad command line ends the program with status 2, like a usage error
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(2)
+}
+
+// the value following an option, `missing` is the error without it
+fn option_value<'a>(options: &mut std::slice::Iter<'a, String>, missing: &str) -> &'a String {
+    options.next().unwrap_or_else(|| usage_error(missing))
+}
+
 fn main() -> io::Result<()> {
     let args: Vec<String> = std::env::args().collect();
 
@@ -307,6 +452,106 @@ fn main() -> io::Result<()> {
     let cart = cpu::cartridge::Cartridge::new(&args[1]);
     cart.extract_info();
     let mut gb = Box::new(Gameboy::origin(cart));
+    // RGB rom.gb [--model auto|dmg0|dmg|mgb|sgb|sgb2|cgb|agb] [--boot-rom file]
+    //     [--skip-boot[=model]] [--scanline] [--palette gray|green|pocket|light|file]
+    //     [--compat-palette combo] [--screenshot-scale n] [--screenshot-palette name|file]
+    //     [--dump-frames dir n] [--record base] [--gif-fps 30|20]
+    let parse_palette = |name: &str| match ram::io::video::palette::Palette::from_name(name) {
+        Some(p) => p,
+        None => ram::io::video::palette::Palette::load(name).unwrap_or_else(|e| usage_error(&e)),
+    };
+    let parse_model = |name: &str| {
+        cpu::boot::Model::from_name(name)
+            .unwrap_or_else(|| usage_error(&format!("unknown model {}", name)))
+    };
+    let mut model = None;
+    let mut boot_rom = None;
+    let mut skip = false;
+    let mut palette = None;
+    let mut record = None;
+    let mut options = args[2..].iter();
+    while let Some(o) = options.next() {
+        match o.as_str() {
+            "--model" => {
+                let name = option_value(&mut options, "--model needs a model");
+                if name != "auto" {
+                    model = Some(parse_model(name));
+                }
+            }
+            "--boot-rom" => {
+                let path = option_value(&mut options, "--boot-rom needs a file");
+                match cpu::boot::load_boot_rom(path) {
+                    Ok(rom) => boot_rom = Some(rom),
+                    Err(e) => usage_error(&e),
+                }
+            }
+            "--skip-boot" => skip = true,
+            "--scanline" => gb.ram.video.renderer = ram::io::video::Renderer::Scanline,
+            "--palette" => {
+                let name = option_value(&mut options, "--palette needs a name or a file");
+                palette = Some(parse_palette(name));
+            }
+            "--screenshot-scale" => {
+                let n = option_value(&mut options, "--screenshot-scale needs a factor");
+                gb.capture.scale = match n.parse() {
+                    Ok(0) | Err(_) => usage_error("the scale is a number above 0"),
+                    Ok(n) => n,
+                };
+            }
+            "--screenshot-palette" => {
+                let name =
+                    option_value(&mut options, "--screenshot-palette needs a name or a file");
+                gb.capture.palette = Some(parse_palette(name));
+            }
+            "--dump-frames" => {
+                let dir = option_value(&mut options, "--dump-frames needs a directory");
+                let every = option_value(&mut options, "--dump-frames needs an interval");
+                let every: u32 = every
+                    .parse()
+                    .unwrap_or_else(|_| usage_error("the interval is a number"));
+                gb.capture.dump = Some((std::path::PathBuf::from(dir), every.max(1)));
+            }
+            "--gif-fps" => {
+                let fps = option_value(&mut options, "--gif-fps needs a frame rate");
+                gb.capture.gif_fps = fps
+                    .parse()
+                    .unwrap_or_else(|_| usage_error("the frame rate is a number"));
+            }
+            "--record" => {
+                let base = option_value(&mut options, "--record needs a file name");
+                record = Some(std::path::PathBuf::from(base));
+            }
+            "--compat-palette" => {
+                let combo = option_value(&mut options, "--compat-palette needs a combo");
+                palette = Some(
+                    ram::io::video::compat::from_combo(combo)
+                        .unwrap_or_else(|| usage_error(&format!("unknown combo {}", combo))),
+                );
+            }
+            o if o.starts_with("--skip-boot=") => {
+                skip = true;
+                model = Some(parse_model(&o["--skip-boot=".len()..]));
+            }
+            o => usage_error(&format!("unknown option {}", o)),
+        }
+    }
+    // the one of the header unless forced, only the DMG boot rom is built in
+    let model = model.unwrap_or_else(|| cpu::boot::Model::from_header(&gb.ram.cart));
+    println!("emulated model {:?}", model);
+    match boot_rom {
+        Some(rom) if !skip => {
+            gb.ram.set_model(model);
+            gb.ram.set_boot_rom(rom);
+        }
+        _ if skip || model != cpu::boot::Model::Dmg => gb.skip_boot(model),
+        _ => gb.ram.set_model(model),
+    }
+    if let Some(p) = palette {
+        gb.ram.video.palette = p;
+    }
+    if let Some(base) = record {
+        gb.start_recording(&base);
+    }
     thread::Builder::new()
         .name("emulator".to_string())
         .spawn(move || {
@@ -0,0 +1,155 @@
+// Recording of the game to a Y4M video and a WAV of the audio samples. Frames
+// are written at each emulated frame (59.73 per second) and samples as the
+// audio makes them, both follow the emulated clock so they stay in sync
+// whatever the speed of the emulation.
+
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// the clocks per second and per frame of the lcd give the frame rate
+const FRAME_RATE: (u32, u32) = (4194304, 70224);
+// the rate of Interrupt::AudioSample
+const SAMPLE_RATE: u32 = 44100;
+// bytes of the wav header before the samples
+const WAV_HEADER: u32 = 44;
+
+pub struct Recorder {
+    base: PathBuf,
+    video: BufWriter<File>,
+    audio: BufWriter<File>,
+    width: usize,
+    height: usize,
+    samples: u32,
+}
+
+fn create(path: &Path) -> Result<BufWriter<File>, String> {
+    File::create(path)
+        .map(BufWriter::new)
+        .map_err(|e| format!("can not create {}: {}", path.display(), e))
+}
+
+// the header of a 32 bits float stereo wav with `samples` samples
+fn wav_header(samples: u32) -> Vec<u8> {
+    let data = samples * 8;
+    let mut h = Vec::with_capacity(WAV_HEADER as usize);
+    h.extend_from_slice(b"RIFF");
+    h.extend_from_slice(&(WAV_HEADER - 8 + data).to_le_bytes());
+    h.extend_from_slice(b"WAVEfmt ");
+    h.extend_from_slice(&16u32.to_le_bytes());
+    // IEEE float, 2 channels
+    h.extend_from_slice(&3u16.to_le_bytes());
+    h.extend_from_slice(&2u16.to_le_bytes());
+    h.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    h.extend_from_slice(&(SAMPLE_RATE * 8).to_le_bytes());
+    h.extend_from_slice(&8u16.to_le_bytes());
+    h.extend_from_slice(&32u16.to_le_bytes());
+    h.extend_from_slice(b"data");
+    h.extend_from_slice(&data.to_le_bytes());
+    h
+}
+
+impl Recorder {
+    // writes base.y4m and base.wav
+    pub fn start(base: &Path, width: usize, height: usize) -> Result<Recorder, String> {
+        let mut video = create(&base.with_extension("y4m"))?;
+        let mut audio = create(&base.with_extension("wav"))?;
+        // full range 4:4:4 to keep the colors
+        writeln!(
+            video,
+            "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C444 XCOLORRANGE=FULL",
+            width, height, FRAME_RATE.0, FRAME_RATE.1
+        )
+        .and_then(|_| audio.write_all(&wav_header(0)))
+        .map_err(|e| format!("can not write {}: {}", base.display(), e))?;
+        Ok(Recorder {
+            base: base.to_path_buf(),
+            video,
+            audio,
+            width,
+            height,
+            samples: 0,
+        })
+    }
+
+    // the first free recording-N in the current directory
+    pub fn next_base() -> PathBuf {
+        (0..)
+            .map(|n| PathBuf::from(format!("recording-{:03}", n)))
+            .find(|p| !p.with_extension("y4m").exists())
+            .unwrap()
+    }
+
+    // `rgba` must have the size given at the start
+    pub fn frame(&mut self, rgba: &[u8]) -> Result<(), String> {
+        let n = self.width * self.height;
+        let mut planes = vec![0; n * 3];
+        for (i, p) in rgba.chunks(4).take(n).enumerate() {
+            let (r, g, b) = (p[0] as f32, p[1] as f32, p[2] as f32);
+            let y = 0.299 * r + 0.587 * g + 0.114 * b;
+            planes[i] = y.round() as u8;
+            planes[n + i] = (128.0 + (b - y) * 0.564).round().clamp(0.0, 255.0) as u8;
+            planes[2 * n + i] = (128.0 + (r - y) * 0.713).round().clamp(0.0, 255.0) as u8;
+        }
+        self.video
+            .write_all(b"FRAME\n")
+            .and_then(|_| self.video.write_all(&planes))
+            .map_err(|e| format!("can not write {}: {}", self.base.display(), e))
+    }
+
+    pub fn sample(&mut self, l: f32, r: f32) -> Result<(), String> {
+        self.samples += 1;
+        self.audio
+            .write_all(&l.to_le_bytes())
+            .and_then(|_| self.audio.write_all(&r.to_le_bytes()))
+            .map_err(|e| format!("can not write {}: {}", self.base.display(), e))
+    }
+
+    // puts the length in the wav header, returns the base name
+    pub fn finish(mut self) -> Result<PathBuf, String> {
+        let samples = self.samples;
+        let audio = &mut self.audio;
+        self.video
+            .flush()
+            .and_then(|_| audio.seek(SeekFrom::Start(0)))
+            .and_then(|_| audio.write_all(&wav_header(samples)))
+            .and_then(|_| audio.flush())
+            .map_err(|e| format!("can not write {}: {}", self.base.display(), e))?;
+        Ok(self.base)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn record_files() {
+        let base = std::env::temp_dir().join(format!("rgb-record-{}", std::process::id()));
+        let mut r = Recorder::start(&base, 2, 1).unwrap();
+        r.frame(&[255, 255, 255, 255, 0, 0, 0, 255]).unwrap();
+        r.frame(&[0, 0, 0, 255, 255, 255, 255, 255]).unwrap();
+        for i in 0..10 {
+            r.sample(i as f32 / 10.0, 0.0).unwrap();
+        }
+        r.finish().unwrap();
+
+        let video = fs::read(base.with_extension("y4m")).unwrap();
+        let header = b"YUV4MPEG2 W2 H1 F4194304:70224 Ip A1:1 C444 XCOLORRANGE=FULL\n";
+        assert!(video.starts_with(header));
+        assert_eq!(video.len(), header.len() + 2 * (6 + 2 * 3));
+        assert_eq!(
+            video[header.len() + 6..header.len() + 12],
+            [255, 0, 128, 128, 128, 128]
+        );
+
+        let audio = fs::read(base.with_extension("wav")).unwrap();
+        assert_eq!(audio.len(), 44 + 10 * 8);
+        assert_eq!(audio[4..8], (36u32 + 80).to_le_bytes());
+        assert_eq!(audio[40..44], 80u32.to_le_bytes());
+        assert_eq!(audio[52..56], 0.1f32.to_le_bytes());
+        fs::remove_file(base.with_extension("y4m")).unwrap();
+        fs::remove_file(base.with_extension("wav")).unwrap();
+    }
+}
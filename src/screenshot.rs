@@ -0,0 +1,284 @@
+// PNG captures of the screen: screenshots asked for with EmuCommand::Screenshot
+// and a numbered dump of every nth frame, for bug reports and regression
+// artifacts. Short animated GIFs are started and stopped with EmuCommand::Gif.
+
+use cpu::ram::io::sgb;
+use cpu::ram::io::video::palette::Palette;
+use cpu::ram::Ram;
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::{Delay, RgbaImage};
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+// the picture shown in RGBA and its size, 256x224 with the SGB border,
+// `palette` replaces the one of the DMG shades
+pub fn screen(ram: &Ram, palette: Option<&Palette>) -> (Vec<u8>, usize, usize) {
+    if ram.sgb.enabled {
+        let frame = ram.sgb.frame(ram.video.lcd_off());
+        (frame, sgb::WIDTH, sgb::HEIGHT)
+    } else if ram.video.lcd_off() {
+        // the CGB shows white, the DMG the lightest shade
+        let frame = if ram.video.cgb {
+            vec![255; 160 * 144 * 4]
+        } else {
+            palette.unwrap_or(&ram.video.palette).rgba(&[0; 160 * 144])
+        };
+        (frame, 160, 144)
+    } else {
+        let frame = ram.video.frame_with(palette.unwrap_or(&ram.video.palette));
+        (frame, 160, 144)
+    }
+}
+
+// every pixel repeated `scale` times in both directions
+pub fn scale(frame: &[u8], width: usize, scale: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(frame.len() * scale * scale);
+    for line in frame.chunks(width * 4) {
+        let mut wide = Vec::with_capacity(line.len() * scale);
+        for pixel in line.chunks(4) {
+            for _ in 0..scale {
+                wide.extend_from_slice(pixel);
+            }
+        }
+        for _ in 0..scale {
+            out.extend_from_slice(&wide);
+        }
+    }
+    out
+}
+
+pub fn save_png(
+    path: &Path,
+    frame: &[u8],
+    width: usize,
+    height: usize,
+    factor: usize,
+) -> Result<(), String> {
+    let data = scale(frame, width, factor);
+    image::save_buffer(
+        path,
+        &data,
+        (width * factor) as u32,
+        (height * factor) as u32,
+        image::ColorType::Rgba8,
+    )
+    .map_err(|e| format!("can not write {}: {}", path.display(), e))
+}
+
+// milliseconds of one lcd frame is 70224 clocks at 4194304 Hz
+const FRAME_MS: f64 = 70224.0 * 1000.0 / 4194304.0;
+
+// an animated gif keeping one frame out of `skip`, the DMG shades make few
+// colors so the encoder gives each frame an exact palette
+pub struct Gif {
+    path: PathBuf,
+    encoder: GifEncoder<BufWriter<File>>,
+    skip: u32,
+    frames: u32,
+    kept: u32,
+}
+
+impl Gif {
+    pub fn start(path: &Path, fps: u32) -> Result<Gif, String> {
+        let file =
+            File::create(path).map_err(|e| format!("can not create {}: {}", path.display(), e))?;
+        let mut encoder = GifEncoder::new(BufWriter::new(file));
+        encoder
+            .set_repeat(Repeat::Infinite)
+            .map_err(|e| format!("can not write {}: {}", path.display(), e))?;
+        Ok(Gif {
+            path: path.to_path_buf(),
+            encoder,
+            skip: (60 / fps.clamp(1, 60)).max(1),
+            frames: 0,
+            kept: 0,
+        })
+    }
+
+    // the gif delays are in hundredths of a second, each one is rounded from
+    // the emulated time so they add up to the length of the recording
+    fn delay(&self) -> u32 {
+        let time = |n: u32| (n as f64 * self.skip as f64 * FRAME_MS / 10.0).round() as u32;
+        (time(self.kept + 1) - time(self.kept)) * 10
+    }
+
+    // called at each emulated frame
+    pub fn frame(&mut self, frame: &[u8], width: usize, height: usize) -> Result<(), String> {
+        let n = self.frames;
+        self.frames += 1;
+        if !n.is_multiple_of(self.skip) {
+            return Ok(());
+        }
+        let buffer = RgbaImage::from_raw(width as u32, height as u32, frame.to_vec())
+            .ok_or_else(|| format!("bad frame size for {}", self.path.display()))?;
+        let delay = Delay::from_numer_denom_ms(self.delay(), 1);
+        self.kept += 1;
+        self.encoder
+            .encode_frame(image::Frame::from_parts(buffer, 0, 0, delay))
+            .map_err(|e| format!("can not write {}: {}", self.path.display(), e))
+    }
+
+    // the trailer is written when the encoder is dropped
+    pub fn finish(self) -> PathBuf {
+        self.path
+    }
+}
+
+pub struct Capture {
+    pub scale: usize,
+    pub palette: Option<Palette>,
+    // the directory and the interval of the frame dump
+    pub dump: Option<(PathBuf, u32)>,
+    frames: u32,
+    pub gif_fps: u32,
+    gif: Option<Gif>,
+}
+
+impl Capture {
+    pub fn origin() -> Capture {
+        Capture {
+            scale: 1,
+            palette: None,
+            dump: None,
+            frames: 0,
+            gif_fps: 30,
+            gif: None,
+        }
+    }
+
+    fn save(&self, ram: &Ram, path: &Path) -> Result<(), String> {
+        let (frame, width, height) = screen(ram, self.palette.as_ref());
+        save_png(path, &frame, width, height, self.scale)
+    }
+
+    // writes screenshot-N.png in the current directory, N the first free number
+    pub fn screenshot(&self, ram: &Ram) -> Result<PathBuf, String> {
+        let path = (0..)
+            .map(|n| PathBuf::from(format!("screenshot-{:03}.png", n)))
+            .find(|p| !p.exists())
+            .unwrap();
+        self.save(ram, &path)?;
+        Ok(path)
+    }
+
+    // called at each vblank, writes frame-N.png when N is a multiple of the interval
+    pub fn frame(&mut self, ram: &Ram) -> Result<(), String> {
+        let n = self.frames;
+        self.frames += 1;
+        let (dir, every) = match &self.dump {
+            Some(d) => d,
+            None => return Ok(()),
+        };
+        if !n.is_multiple_of(*every) {
+            return Ok(());
+        }
+        if n == 0 {
+            fs::create_dir_all(dir)
+                .map_err(|e| format!("can not create {}: {}", dir.display(), e))?;
+        }
+        let path = dir.join(format!("frame-{:06}.png", n));
+        self.save(ram, &path)
+    }
+
+    // the path of the gif that was going on
+    pub fn stop_gif(&mut self) -> Option<PathBuf> {
+        self.gif.take().map(Gif::finish)
+    }
+
+    // starts capture-N.gif or stops the one going on, returns its path and
+    // whether it started
+    pub fn toggle_gif(&mut self) -> Result<(PathBuf, bool), String> {
+        if let Some(path) = self.stop_gif() {
+            return Ok((path, false));
+        }
+        let path = (0..)
+            .map(|n| PathBuf::from(format!("capture-{:03}.gif", n)))
+            .find(|p| !p.exists())
+            .unwrap();
+        self.gif = Some(Gif::start(&path, self.gif_fps)?);
+        Ok((path, true))
+    }
+
+    // called at each emulated frame, with the lcd on or off
+    pub fn gif_frame(&mut self, ram: &Ram) -> Result<(), String> {
+        let (frame, width, height) = match &self.gif {
+            Some(_) => screen(ram, self.palette.as_ref()),
+            None => return Ok(()),
+        };
+        let frame = scale(&frame, width, self.scale);
+        let gif = self.gif.as_mut().unwrap();
+        let result = gif.frame(&frame, width * self.scale, height * self.scale);
+        if result.is_err() {
+            self.gif = None;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::bus::Bus;
+    use cpu::cartridge::Cartridge;
+    use cpu::ram::io::Video;
+    use image::codecs::gif::GifDecoder;
+    use image::AnimationDecoder;
+
+    #[test]
+    fn frame_dump() {
+        let pixels = [1, 2, 3, 255, 4, 5, 6, 255];
+        let big = scale(&pixels, 2, 2);
+        assert_eq!(big.len(), 8 * 4);
+        assert_eq!(big[8..12], [4, 5, 6, 255]);
+        assert_eq!(big[16..20], [1, 2, 3, 255]);
+
+        let dir = std::env::temp_dir().join(format!("rgb-frames-{}", std::process::id()));
+        let mut ram = Ram::origin(Cartridge::default());
+        ram.video.palette = Palette::from_name("pocket").unwrap();
+        let (blank, _, _) = screen(&ram, None);
+        let lightest = ram.video.palette.bg[0];
+        assert_eq!(blank[4 * 100..4 * 100 + 3], lightest, "lcd off");
+        // the first frame after turning the lcd on is not shown
+        ram.write(0xff40, 0x91);
+        for clock in 0..154 * 114 {
+            Video::step(&mut ram, clock);
+        }
+        ram.video.back_buffer = [3; 160 * 144];
+        let mut capture = Capture::origin();
+        capture.scale = 2;
+        capture.palette = Palette::from_name("green");
+        capture.dump = Some((dir.clone(), 2));
+        for _ in 0..3 {
+            capture.frame(&ram).unwrap();
+        }
+        assert!(!dir.join("frame-000001.png").exists());
+        let png = image::open(dir.join("frame-000002.png"))
+            .unwrap()
+            .to_rgba8();
+        assert_eq!(png.dimensions(), (320, 288));
+        assert_eq!(png.get_pixel(5, 5).0, [0x0f, 0x38, 0x0f, 255]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn gif_capture() {
+        let path = std::env::temp_dir().join(format!("rgb-capture-{}.gif", std::process::id()));
+        let mut gif = Gif::start(&path, 30).unwrap();
+        for n in 0..6 {
+            let shade = [n as u8 * 40, 0, 0, 255];
+            gif.frame(&shade.repeat(4 * 2), 4, 2).unwrap();
+        }
+        gif.finish();
+
+        let file = File::open(&path).unwrap();
+        let frames = GifDecoder::new(file).unwrap().into_frames();
+        let frames = frames.collect_frames().unwrap();
+        assert_eq!(frames.len(), 3);
+        // 33.5 ms per frame at 30 fps
+        let delays: Vec<_> = frames.iter().map(|f| f.delay().numer_denom_ms()).collect();
+        assert_eq!(delays, [(30, 1), (40, 1), (30, 1)]);
+        assert_eq!(frames[1].buffer().get_pixel(3, 1).0, [80, 0, 0, 255]);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,444 @@
+// Runs the test roms found in test-roms/ without window nor sound.
+// Every test is skipped when its roms are not there, see README.md for the layout.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use cpu;
+use cpu::Bus;
+use Gameboy;
+
+const TEST_ROMS: &str = "test-roms";
+
+// clocks are the 1MHz machine cycles counted by Gameboy::step
+const SECOND: u64 = 1 << 20;
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Passed,
+    Failed,
+    Timeout,
+    Skipped,
+}
+
+pub struct Report {
+    pub rom: PathBuf,
+    pub outcome: Outcome,
+    pub text: String,
+    pub sub_tests: Vec<(String, bool)>,
+}
+
+pub fn find_roms(dir: &Path) -> Vec<PathBuf> {
+    let mut out = Vec::new();
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return out,
+    };
+    for e in entries.filter_map(|e| e.ok()) {
+        let path = e.path();
+        if path.is_dir() {
+            out.extend(find_roms(&path));
+        } else if let Some("gb") | Some("gbc") = path.extension().and_then(|x| x.to_str()) {
+            out.push(path);
+        }
+    }
+    out.sort();
+    out
+}
+
+pub fn test_dir(sub: &str) -> Option<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join(TEST_ROMS)
+        .join(sub);
+    if dir.is_dir() {
+        Some(dir)
+    } else {
+        println!("skipping, {} not found", dir.display());
+        None
+    }
+}
+
+pub fn boot(rom: &Path) -> Box<Gameboy> {
+    let cart = cpu::cartridge::Cartridge::new(rom.to_str().expect("rom path is not utf8"));
+    Box::new(Gameboy::origin(cart))
+}
+
+// blargg roms write a text signature at 0xa000 when the cartridge has ram:
+// a001..=a003 is de b0 61, a000 the status (0x80 while running) and the text
+// starts at a004
+fn read_signature(gb: &Gameboy) -> Option<(u8, String)> {
+    let ram = &gb.ram;
+    if ram.peek(0xa001) != 0xde || ram.peek(0xa002) != 0xb0 || ram.peek(0xa003) != 0x61 {
+        return None;
+    }
+    let mut text = String::new();
+    for a in 0xa004..0xc000 {
+        match ram.peek(a) {
+            0 => break,
+            c => text.push(c as char),
+        }
+    }
+    Some((ram.peek(0xa000), text))
+}
+
+fn sub_tests(rom: &Path, text: &str, outcome: &Outcome) -> Vec<(String, bool)> {
+    // multi roms print "01:ok  02:ok  03:01 ..." , single ones only the verdict
+    let list: Vec<(String, bool)> = text
+        .split_whitespace()
+        .filter_map(|w| {
+            let (n, r) = w.split_once(':')?;
+            if n.len() == 2 && n.chars().all(|c| c.is_ascii_digit()) && !r.is_empty() {
+                Some((n.to_string(), r == "ok"))
+            } else {
+                None
+            }
+        })
+        .collect();
+    if !list.is_empty() {
+        list
+    } else {
+        let name = rom
+            .file_stem()
+            .map_or(String::new(), |s| s.to_string_lossy().into_owned());
+        vec![(name, *outcome == Outcome::Passed)]
+    }
+}
+
+pub fn run_blargg(rom: &Path, budget: u64) -> Report {
+    let mut gb = boot(rom);
+    gb.ram.serial.capture = Some(Vec::new());
+    let mut outcome = Outcome::Timeout;
+    let mut text = String::new();
+    let mut seen = 0;
+    for clock in 0..budget {
+        gb.step();
+        if clock & 0xffff != 0 {
+            continue;
+        }
+        if let Some((status, t)) = read_signature(&gb) {
+            if status != 0x80 {
+                outcome = if status == 0 {
+                    Outcome::Passed
+                } else {
+                    Outcome::Failed
+                };
+                text = t;
+                break;
+            }
+        }
+        let serial = gb.ram.serial.capture.as_ref().unwrap();
+        if serial.len() != seen {
+            seen = serial.len();
+            text = String::from_utf8_lossy(serial).into_owned();
+            if text.contains("Passed") {
+                outcome = Outcome::Passed;
+                break;
+            } else if text.contains("Failed") {
+                outcome = Outcome::Failed;
+                break;
+            }
+        }
+    }
+    let sub_tests = sub_tests(rom, &text, &outcome);
+    Report {
+        rom: rom.to_path_buf(),
+        outcome,
+        text,
+        sub_tests,
+    }
+}
+
+pub fn print_reports(reports: &[Report]) {
+    for r in reports {
+        println!("{:?} {}", r.outcome, r.rom.display());
+        for (name, ok) in &r.sub_tests {
+            println!("    {:<24} {}", name, if *ok { "ok" } else { "FAILED" });
+        }
+        if r.outcome != Outcome::Passed {
+            for l in r.text.lines() {
+                println!("    | {}", l);
+            }
+        }
+    }
+}
+
+// mooneye roms end with LD B,B, the registers then hold 3 5 8 13 21 34 on
+// success and 0x42 everywhere on failure
+pub fn run_mooneye(rom: &Path, budget: u64) -> Report {
+    let mut gb = boot(rom);
+    let mut outcome = Outcome::Timeout;
+    let mut text = String::new();
+    for _ in 0..budget {
+        gb.step();
+        if gb.breakpoint {
+            let r = &gb.cpu.reg;
+            outcome = if [r.b, r.c, r.d, r.e, r.h, r.l] == [3, 5, 8, 13, 21, 34] {
+                Outcome::Passed
+            } else {
+                Outcome::Failed
+            };
+            text = format!("{}", gb.cpu);
+            break;
+        }
+    }
+    let name = rom
+        .file_stem()
+        .map_or(String::new(), |s| s.to_string_lossy().into_owned());
+    let ok = outcome == Outcome::Passed;
+    Report {
+        rom: rom.to_path_buf(),
+        outcome,
+        text,
+        sub_tests: vec![(name, ok)],
+    }
+}
+
+// reference screenshots are matched to the 4 shades by brightness
+fn shade(v: u8) -> u8 {
+    match v {
+        0xc0..=0xff => 0,
+        0x6e..=0xbf => 1,
+        0x30..=0x6d => 2,
+        _ => 3,
+    }
+}
+
+// runs until LD B,B and compares the screen with the png of the same name,
+// the screen is saved next to it as .actual.png when they differ
+pub fn run_screenshot(rom: &Path, budget: u64) -> Report {
+    let mut gb = boot(rom);
+    let mut outcome = Outcome::Timeout;
+    let mut text = String::new();
+    for _ in 0..budget {
+        gb.step();
+        if gb.breakpoint {
+            break;
+        }
+    }
+    let reference = rom.with_extension("png");
+    match image::open(&reference) {
+        Err(e) => text = format!("no reference {}: {}", reference.display(), e),
+        Ok(img) => {
+            let img = img.to_luma8();
+            let screen = &gb.ram.video.back_buffer;
+            let wrong = if img.dimensions() != (160, 144) {
+                screen.len()
+            } else {
+                img.pixels()
+                    .zip(screen.iter())
+                    .filter(|(r, s)| shade(r[0]) != **s & 3)
+                    .count()
+            };
+            if wrong == 0 {
+                outcome = Outcome::Passed;
+            } else {
+                if gb.breakpoint {
+                    outcome = Outcome::Failed;
+                }
+                text = format!("{} pixels differ", wrong);
+                let actual = rom.with_extension("actual.png");
+                let frame = gb.ram.video.frame();
+                let _ = image::save_buffer(&actual, &frame, 160, 144, image::ColorType::Rgba8);
+            }
+        }
+    }
+    let name = rom
+        .file_stem()
+        .map_or(String::new(), |s| s.to_string_lossy().into_owned());
+    let ok = outcome == Outcome::Passed;
+    Report {
+        rom: rom.to_path_buf(),
+        outcome,
+        text,
+        sub_tests: vec![(name, ok)],
+    }
+}
+
+// mooneye rom names end with the models they are meant for, like
+// -dmgABCmgb, -GS or -C, the others run everywhere
+fn meant_for_dmg(rom: &Path) -> bool {
+    let stem = rom
+        .file_stem()
+        .map_or(String::new(), |s| s.to_string_lossy().into_owned());
+    let mut tag = match stem.rfind('-') {
+        Some(i) => &stem[i + 1..],
+        None => return true,
+    };
+    let mut dmg = false;
+    while !tag.is_empty() {
+        if let Some(m) = ["dmg", "mgb", "sgb", "cgb", "agb", "ags"]
+            .iter()
+            .find(|m| tag.starts_with(*m))
+        {
+            let revision: String = tag[3..]
+                .chars()
+                .take_while(|c| c.is_ascii_digit() || ('A'..='E').contains(c))
+                .collect();
+            if *m == "dmg" && (revision.is_empty() || revision.contains('A')) {
+                dmg = true;
+            }
+            tag = &tag[3 + revision.len()..];
+        } else if tag.starts_with('G') {
+            dmg = true;
+            tag = &tag[1..];
+        } else if tag.starts_with('S') || tag.starts_with('C') || tag.starts_with('A') {
+            tag = &tag[1..];
+        } else {
+            // not a model list, only a part of the name
+            return true;
+        }
+    }
+    dmg
+}
+
+fn mooneye_dir(sub: &str) {
+    let dir = match test_dir(&format!("mooneye/{}", sub)) {
+        Some(d) => d,
+        None => return,
+    };
+    let reports: Vec<Report> = find_roms(&dir)
+        .iter()
+        .filter(|r| r.parent() == Some(&dir))
+        .map(|r| {
+            if meant_for_dmg(r) {
+                run_mooneye(r, 10 * SECOND)
+            } else {
+                Report {
+                    rom: r.to_path_buf(),
+                    outcome: Outcome::Skipped,
+                    text: String::new(),
+                    sub_tests: Vec::new(),
+                }
+            }
+        })
+        .collect();
+    println!("{:<24} {:<48} result", "suite", "rom");
+    for r in &reports {
+        let name = r.rom.file_name().unwrap().to_string_lossy();
+        println!("{:<24} {:<48} {:?} {}", sub, name, r.outcome, r.text);
+    }
+    let passed = reports
+        .iter()
+        .filter(|r| r.outcome == Outcome::Passed)
+        .count();
+    let run = reports
+        .iter()
+        .filter(|r| r.outcome != Outcome::Skipped)
+        .count();
+    println!("{}: {} of {} passed", sub, passed, run);
+    assert!(passed == run, "{} of {} roms failed", run - passed, run);
+}
+
+fn blargg_dir(sub: &str, budget: u64) {
+    let dir = match test_dir(sub) {
+        Some(d) => d,
+        None => return,
+    };
+    let reports: Vec<Report> = find_roms(&dir)
+        .iter()
+        .map(|r| run_blargg(r, budget))
+        .collect();
+    print_reports(&reports);
+    let failed = reports
+        .iter()
+        .filter(|r| r.outcome != Outcome::Passed)
+        .count();
+    assert!(failed == 0, "{} of {} roms failed", failed, reports.len());
+}
+
+fn screenshot_dir(sub: &str) {
+    let dir = match test_dir(sub) {
+        Some(d) => d,
+        None => return,
+    };
+    let reports: Vec<Report> = find_roms(&dir)
+        .iter()
+        .map(|r| run_screenshot(r, 10 * SECOND))
+        .collect();
+    print_reports(&reports);
+    let failed = reports
+        .iter()
+        .filter(|r| r.outcome != Outcome::Passed)
+        .count();
+    assert!(failed == 0, "{} of {} roms failed", failed, reports.len());
+}
+
+#[test]
+fn blargg_cpu_instrs() {
+    blargg_dir("blargg/cpu_instrs", 120 * SECOND);
+}
+
+#[test]
+fn blargg_instr_timing() {
+    blargg_dir("blargg/instr_timing", 10 * SECOND);
+}
+
+#[test]
+fn blargg_oam_bug() {
+    blargg_dir("blargg/oam_bug", 30 * SECOND);
+}
+
+#[test]
+fn mooneye_acceptance() {
+    mooneye_dir("acceptance");
+}
+
+#[test]
+fn mooneye_acceptance_bits() {
+    mooneye_dir("acceptance/bits");
+}
+
+#[test]
+fn mooneye_acceptance_instr() {
+    mooneye_dir("acceptance/instr");
+}
+
+#[test]
+fn mooneye_acceptance_interrupts() {
+    mooneye_dir("acceptance/interrupts");
+}
+
+#[test]
+fn mooneye_acceptance_oam_dma() {
+    mooneye_dir("acceptance/oam_dma");
+}
+
+#[test]
+fn mooneye_acceptance_ppu() {
+    mooneye_dir("acceptance/ppu");
+}
+
+#[test]
+fn mooneye_acceptance_serial() {
+    mooneye_dir("acceptance/serial");
+}
+
+#[test]
+fn mooneye_acceptance_timer() {
+    mooneye_dir("acceptance/timer");
+}
+
+#[test]
+fn mooneye_mbc1() {
+    mooneye_dir("emulator-only/mbc1");
+}
+
+#[test]
+fn mooneye_mbc2() {
+    mooneye_dir("emulator-only/mbc2");
+}
+
+#[test]
+fn mooneye_mbc5() {
+    mooneye_dir("emulator-only/mbc5");
+}
+
+#[test]
+fn dmg_acid2() {
+    screenshot_dir("dmg-acid2");
+}
+
+#[test]
+fn window_screenshots() {
+    screenshot_dir("window");
+}
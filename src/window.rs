@@ -57,7 +57,6 @@ impl App {
 
         const GREEN: [f32; 4] = [0.0, 1.0, 1.0, 1.0];
 
-        let main_screen = Image::new().rect([256.0,256.0,160.0,144.0]);
         let window0_screen = Image::new().rect([0.0,0.0,256.0,256.0]);
         let window1_screen = Image::new().rect([256.0,0.0,256.0,256.0]);
 
@@ -75,6 +74,9 @@ impl App {
             clear(GREEN, gl);
 
             if let Some(b) = buff{
+                // 160x144, or 256x224 with the SGB border
+                let (w, h) = b.get_size();
+                let main_screen = Image::new().rect([256.0,256.0,w as f64,h as f64]);
                 main_screen.draw(b, &c.draw_state, c.transform, gl);
             }
             if let Some(img) = w0{
@@ -104,7 +106,6 @@ impl App {
                 let updated_w0 = msg.window0.is_some();
                 let updated_w1 = msg.window1.is_some();
                 let updated_s = msg.tileset.is_some();
-                let mut ar: [u8; 160 * 144 * 4] = [128; 160 * 144 * 4];
 
                 let mut h: std::string::String = "".to_string();
                 let mut sep = " ";
@@ -120,13 +121,8 @@ impl App {
                 }
                 self.hram = Some(hram_list);
 
-                for i in 0..msg.back_buffer.len() {
-                    ar[i * 4] = msg.back_buffer[i];
-                    ar[i * 4 + 1] = msg.back_buffer[i];
-                    ar[i * 4 + 2] = msg.back_buffer[i];
-                    ar[i * 4 + 3] = 255;
-                }
-                self.buffer = Some(opengl_graphics::Texture::from_image(&ImageBuffer::from_raw(160, 144, ar.to_vec()).unwrap(), &opengl_graphics::TextureSettings::new()));
+                let ar = msg.frame;
+                self.buffer = Some(opengl_graphics::Texture::from_image(&ImageBuffer::from_raw(msg.width as u32, msg.height as u32, ar).unwrap(), &opengl_graphics::TextureSettings::new()));
 
                 // opengl_graphics::Texture::from_memory_alpha(&ar, 160, 144, &opengl_graphics::TextureSettings::new()).ok();
                 //self.buffer = graphics::Image::from_rgba8(_ctx, 160, 144, &ar).unwrap();
@@ -318,6 +314,12 @@ pub fn main_loop(rx: mpsc::Receiver<ToDisplay>,tx: mpsc::Sender<ToEmu>){
                                     ButtonState::Press => ToEmu::KeyDown(key),
                                     ButtonState::Release => ToEmu::KeyUp(key), 
                                 }).expect("noooooo");
+                            }else if k == Key::F12 && b.state == ButtonState::Press {
+                                app.tx.send(ToEmu::Command(EmuCommand::Screenshot)).expect("no screenshot ?");
+                            }else if k == Key::F11 && b.state == ButtonState::Press {
+                                app.tx.send(ToEmu::Command(EmuCommand::Record)).expect("no record ?");
+                            }else if k == Key::F10 && b.state == ButtonState::Press {
+                                app.tx.send(ToEmu::Command(EmuCommand::Gif)).expect("no gif ?");
                             }else{
                                 if k == Key::Escape{
                                     let msg = ToEmu::Command(EmuCommand::Save);